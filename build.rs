@@ -1,7 +1,11 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
     // tonic_build::configure()
     //     .out_dir("./")
     //     .compile(&["proto/pg_fdw.proto"], &["proto/"])?;
     tonic_build::compile_protos("proto/pg_fdw.proto")?;
+    tonic_build::compile_protos("proto/google/rpc/status.proto")?;
+    tonic_build::compile_protos("proto/grpc/health/v1/health.proto")?;
+    tonic_build::compile_protos("proto/grpc/reflection/v1alpha/reflection.proto")?;
     Ok(())
 }
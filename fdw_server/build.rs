@@ -1,4 +1,7 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
     tonic_build::compile_protos("../proto/pg_fdw.proto")?;
+    tonic_build::compile_protos("../proto/grpc/health/v1/health.proto")?;
+    tonic_build::compile_protos("proto/acme/users/v1/users.proto")?;
     Ok(())
 }
@@ -0,0 +1,11 @@
+
+
+acme/users/v1/users.protoacme.users.v1"/
+ListUsersRequest
+	page_size (RpageSize"Q
+User
+id (Rid!
+display_name (	RdisplayName
+avatar (Ravatar2R
+UserServiceC
+	ListUsers.acme.users.v1.ListUsersRequest.acme.users.v1.User0bproto3
\ No newline at end of file
@@ -1,21 +1,55 @@
+use acme::users::v1::user_service_server::{UserService, UserServiceServer};
+use acme::users::v1::{ListUsersRequest, User};
 use futures::Stream;
+use health::health_check_response::ServingStatus;
+use health::health_server::{Health, HealthServer};
+use health::{HealthCheckRequest, HealthCheckResponse};
 use pg::fdw_server::{Fdw, FdwServer};
-use pg::{DeleteRequest, ExecuteRequest, InsertRequest, ResultSet, UpdateRequest};
-use prost_types::Value;
+use pg::{
+    DeleteRequest, ExecuteRequest, ExplainResponse, InsertRequest, Notice, NoticeLevel, Partition,
+    PlanPartitionsResponse, ResultSet, UpdateRequest,
+};
+use prost_types::value::Kind;
+use prost_types::{ListValue, Struct, Value};
+use std::collections::HashMap;
 use std::pin::Pin;
 use std::sync::Arc;
+use tokio::net::UnixListener;
 use tokio::sync::mpsc;
+use tokio_stream::wrappers::UnixListenerStream;
+use tonic::codec::CompressionEncoding;
 use tonic::{transport::Server, Request, Response, Status};
 
 pub mod pg {
     tonic::include_proto!("pg");
 }
 
+pub mod health {
+    tonic::include_proto!("grpc.health.v1");
+}
+
+pub mod acme {
+    pub mod users {
+        pub mod v1 {
+            tonic::include_proto!("acme.users.v1");
+        }
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct EchoFdw {
+    /// Rows of the tables with rows of their own.
+    tables: HashMap<String, Arc<Vec<ResultSet>>>,
+    /// Rows of every other table.
     rows: Arc<Vec<ResultSet>>,
 }
 
+impl EchoFdw {
+    fn rows(&self, table: &str) -> Arc<Vec<ResultSet>> {
+        self.tables.get(table).unwrap_or(&self.rows).clone()
+    }
+}
+
 #[tonic::async_trait]
 impl Fdw for EchoFdw {
     type ExecuteStream =
@@ -32,13 +66,25 @@ impl Fdw for EchoFdw {
 
     async fn execute(
         &self,
-        _request: Request<ExecuteRequest>,
+        request: Request<ExecuteRequest>,
     ) -> Result<Response<Self::ExecuteStream>, Status> {
+        let request = request.into_inner();
+        let rows = self.rows(&request.table);
+        let range = if request.partition.is_empty() {
+            0..rows.len()
+        } else {
+            let index = std::str::from_utf8(&request.partition)
+                .ok()
+                .and_then(|index| index.parse::<usize>().ok())
+                .filter(|index| *index < rows.len())
+                .ok_or_else(|| Status::invalid_argument("unknown partition"))?;
+            index..index + 1
+        };
+
         let (tx, rx) = mpsc::channel(4);
-        let rows = self.rows.clone();
 
         tokio::spawn(async move {
-            for row in &rows[..] {
+            for row in &rows[range] {
                 tx.send(Ok(row.clone())).await.unwrap()
             }
         });
@@ -50,10 +96,17 @@ impl Fdw for EchoFdw {
 
     async fn insert(
         &self,
-        _request: Request<InsertRequest>,
+        request: Request<InsertRequest>,
     ) -> Result<tonic::Response<Self::InsertStream>, Status> {
-        let (_tx, rx) = mpsc::channel(4);
-        let mut _rows = &self.rows;
+        let request = request.into_inner();
+        let rx = notify(
+            format!(
+                "insert into {}: {} values",
+                request.table,
+                request.tuples.len()
+            ),
+            &request.tuples,
+        );
 
         Ok(Response::new(Box::pin(
             tokio_stream::wrappers::ReceiverStream::new(rx),
@@ -62,10 +115,13 @@ impl Fdw for EchoFdw {
 
     async fn update(
         &self,
-        _request: Request<UpdateRequest>,
+        request: Request<UpdateRequest>,
     ) -> Result<tonic::Response<Self::UpdateStream>, Status> {
-        let (_tx, rx) = mpsc::channel(4);
-        let mut _rows = &self.rows;
+        let request = request.into_inner();
+        let rx = notify(
+            format!("update {}: {} values", request.table, request.tuples.len()),
+            &request.tuples,
+        );
 
         Ok(Response::new(Box::pin(
             tokio_stream::wrappers::ReceiverStream::new(rx),
@@ -74,24 +130,231 @@ impl Fdw for EchoFdw {
 
     async fn delete(
         &self,
-        _request: Request<DeleteRequest>,
+        request: Request<DeleteRequest>,
     ) -> Result<Response<Self::DeleteStream>, tonic::Status> {
-        let (_tx, rx) = mpsc::channel(4);
-        let mut _rows = &self.rows;
+        let request = request.into_inner();
+        let rx = notify(
+            format!(
+                "delete from {}: {} indices",
+                request.table,
+                request.indices.len()
+            ),
+            &request.indices,
+        );
 
         Ok(Response::new(Box::pin(
             tokio_stream::wrappers::ReceiverStream::new(rx),
         )))
     }
+
+    async fn explain(
+        &self,
+        request: Request<ExecuteRequest>,
+    ) -> Result<Response<ExplainResponse>, Status> {
+        let request = request.into_inner();
+        let rows = Value {
+            kind: Some(Kind::NumberValue(self.rows(&request.table).len() as f64)),
+        };
+
+        Ok(Response::new(ExplainResponse {
+            // `\0` in a table name stands for a NUL byte, which clients must
+            // cope with in plans.
+            plan: format!("Echo rows of {}", request.table.replace("\\0", "\0")),
+            properties: Some(Struct {
+                fields: [(String::from("Echoed Rows"), rows)].into_iter().collect(),
+            }),
+        }))
+    }
+
+    async fn plan_partitions(
+        &self,
+        request: Request<ExecuteRequest>,
+    ) -> Result<Response<PlanPartitionsResponse>, Status> {
+        Ok(Response::new(PlanPartitionsResponse {
+            partitions: partitions(self.rows(&request.into_inner().table).len()),
+        }))
+    }
+}
+
+/// Every row is a partition of its own, named by its index.
+fn partitions(rows: usize) -> Vec<Partition> {
+    (0..rows)
+        .map(|index| Partition {
+            token: index.to_string().into_bytes(),
+            rows: 1.0,
+        })
+        .collect()
+}
+
+fn number(n: f64) -> Value {
+    Value {
+        kind: Some(Kind::NumberValue(n)),
+    }
+}
+
+fn string(s: &str) -> Value {
+    Value {
+        kind: Some(Kind::StringValue(s.into())),
+    }
+}
+
+/// Rows `1` to `n` of the `series` table, one partition each.
+fn series(n: usize) -> Vec<ResultSet> {
+    (1..=n)
+        .map(|id| ResultSet {
+            values: vec![
+                number(id as f64),
+                string(&format!("Row {}", id)),
+                string("PG-FDWServer"),
+            ],
+            notice: None,
+        })
+        .collect()
+}
+
+/// The row of the `edge_values` table, values at the edges of what columns
+/// can hold.
+fn edge_values() -> ResultSet {
+    ResultSet {
+        values: vec![
+            number(32768.0),
+            number(1.5),
+            number(f64::NAN),
+            number(f64::NEG_INFINITY),
+            number(9_223_372_036_854_775_808.0),
+            string("AQI="),
+        ],
+        notice: None,
+    }
+}
+
+/// `grpc.health.v1.Health`, reporting the server and the `pg.Fdw` service as
+/// serving for as long as the process is up.
+#[derive(Debug, Default)]
+pub struct EchoHealth;
+
+impl EchoHealth {
+    fn status(service: &str) -> Option<ServingStatus> {
+        match service {
+            "" | "pg.Fdw" => Some(ServingStatus::Serving),
+            _ => None,
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Health for EchoHealth {
+    type WatchStream =
+        Pin<Box<dyn Stream<Item = Result<HealthCheckResponse, Status>> + Send + Sync + 'static>>;
+
+    async fn check(
+        &self,
+        request: Request<HealthCheckRequest>,
+    ) -> Result<Response<HealthCheckResponse>, Status> {
+        let service = request.into_inner().service;
+        match Self::status(&service) {
+            Some(status) => Ok(Response::new(HealthCheckResponse {
+                status: status as i32,
+            })),
+            None => Err(Status::not_found(format!("unknown service {}", service))),
+        }
+    }
+
+    async fn watch(
+        &self,
+        request: Request<HealthCheckRequest>,
+    ) -> Result<Response<Self::WatchStream>, Status> {
+        let status =
+            Self::status(&request.into_inner().service).unwrap_or(ServingStatus::ServiceUnknown);
+        let response = HealthCheckResponse {
+            status: status as i32,
+        };
+
+        Ok(Response::new(Box::pin(futures::stream::iter(vec![Ok(
+            response,
+        )]))))
+    }
+}
+
+/// Echoes a modification back to the client as a notice, with the values it
+/// was sent as JSON in its detail.
+fn notify(message: String, values: &[Value]) -> mpsc::Receiver<Result<ResultSet, Status>> {
+    let (tx, rx) = mpsc::channel(1);
+    let notice = ResultSet {
+        values: vec![],
+        notice: Some(Notice {
+            level: NoticeLevel::Notice as i32,
+            message,
+            detail: json(&Value {
+                kind: Some(Kind::ListValue(ListValue {
+                    values: values.to_vec(),
+                })),
+            }),
+        }),
+    };
+
+    tx.try_send(Ok(notice)).unwrap();
+    rx
+}
+
+fn json(value: &Value) -> String {
+    match &value.kind {
+        None | Some(Kind::NullValue(_)) => String::from("null"),
+        Some(Kind::NumberValue(n)) => n.to_string(),
+        Some(Kind::StringValue(s)) => format!("{:?}", s),
+        Some(Kind::BoolValue(b)) => b.to_string(),
+        Some(Kind::StructValue(s)) => {
+            let fields: Vec<String> = s
+                .fields
+                .iter()
+                .map(|(key, value)| format!("{:?}: {}", key, json(value)))
+                .collect();
+            format!("{{{}}}", fields.join(", "))
+        }
+        Some(Kind::ListValue(list)) => {
+            let values: Vec<String> = list.values.iter().map(json).collect();
+            format!("[{}]", values.join(", "))
+        }
+    }
+}
+
+/// `acme.users.v1.UserService`, a service of its own mapped with a table's
+/// `method` option instead of going through `pg.Fdw`.
+#[derive(Debug, Default)]
+pub struct EchoUsers {
+    users: Arc<Vec<User>>,
 }
 
+#[tonic::async_trait]
+impl UserService for EchoUsers {
+    type ListUsersStream =
+        Pin<Box<dyn Stream<Item = Result<User, Status>> + Send + Sync + 'static>>;
+
+    async fn list_users(
+        &self,
+        request: Request<ListUsersRequest>,
+    ) -> Result<Response<Self::ListUsersStream>, Status> {
+        let users = match request.into_inner().page_size {
+            0 => self.users.len(),
+            n => (n.max(0) as usize).min(self.users.len()),
+        };
+        let users: Vec<_> = self.users[..users].iter().cloned().map(Ok).collect();
+
+        Ok(Response::new(Box::pin(tokio_stream::iter(users))))
+    }
+}
+
+/// Listens on `[::1]:50051` unless given another address, either
+/// `host:port` or `unix:///path/to.sock`.
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let addr = "[::1]:50051".parse()?;
+    let listen = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| String::from("[::1]:50051"));
     let result = ResultSet {
         values: vec![
             Value {
-                kind: Some(prost_types::value::Kind::NumberValue(1 as f64)),
+                kind: Some(prost_types::value::Kind::NumberValue(1_f64)),
             },
             Value {
                 kind: Some(prost_types::value::Kind::StringValue(
@@ -102,16 +365,58 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 kind: Some(prost_types::value::Kind::StringValue("PG-FDWServer".into())),
             },
         ],
+        notice: None,
     };
 
     let fdw = EchoFdw {
+        tables: [
+            (String::from("series"), Arc::new(series(4))),
+            (String::from("edge_values"), Arc::new(vec![edge_values()])),
+        ]
+        .into_iter()
+        .collect(),
         rows: Arc::new(vec![result]),
     };
 
-    Server::builder()
-        .add_service(FdwServer::new(fdw))
-        .serve(addr)
-        .await?;
+    let users = EchoUsers {
+        users: Arc::new(vec![
+            User {
+                id: 1,
+                display_name: "Ada".into(),
+                avatar: vec![0x01, 0xff],
+            },
+            User {
+                id: 2,
+                display_name: "Grace".into(),
+                avatar: vec![],
+            },
+        ]),
+    };
+
+    let server = Server::builder()
+        .add_service(
+            FdwServer::new(fdw)
+                .accept_compressed(CompressionEncoding::Gzip)
+                .accept_compressed(CompressionEncoding::Zstd)
+                .send_compressed(CompressionEncoding::Gzip)
+                .send_compressed(CompressionEncoding::Zstd),
+        )
+        .add_service(
+            HealthServer::new(EchoHealth)
+                .accept_compressed(CompressionEncoding::Gzip)
+                .accept_compressed(CompressionEncoding::Zstd)
+                .send_compressed(CompressionEncoding::Gzip)
+                .send_compressed(CompressionEncoding::Zstd),
+        )
+        .add_service(UserServiceServer::new(users));
+    match listen.strip_prefix("unix://") {
+        Some(path) => {
+            let _ = std::fs::remove_file(path);
+            let incoming = UnixListenerStream::new(UnixListener::bind(path)?);
+            server.serve_with_incoming(incoming).await?;
+        }
+        None => server.serve(listen.parse()?).await?,
+    }
 
     Ok(())
 }
@@ -1,81 +1,554 @@
+use crate::codec::{CodecOptions, ACCEPTED};
+use crate::connection::{self, block_on, Call, Connection};
+use crate::error::{self, report_notice, RemoteError};
+use crate::metadata;
+use crate::options::ForeignOptions;
+use crate::retry::RetryPolicy;
+use crate::stats::{Attempt, Sent};
+use crate::{dynamic, reflection};
 use pg::{
-    fdw_client::FdwClient, DeleteRequest, ExecuteRequest, InsertRequest, ResultSet, UpdateRequest,
+    fdw_client::FdwClient, DeleteRequest, ExecuteRequest, ExplainResponse, InsertRequest,
+    Partition, ResultSet, UpdateRequest,
 };
-use pgx::warning;
-use tokio::runtime::{Builder, Runtime};
+use pgx::*;
+use prost::Message;
+use std::future::Future;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+use tonic::codec::Streaming;
+use tonic::metadata::MetadataMap;
+use tonic::transport::Channel;
+use tonic::Code;
 
 pub mod pg {
     tonic::include_proto!("pg");
 }
 
+const EXECUTE: &str = "pg.Fdw/Execute";
+
 pub type StdError = Box<dyn std::error::Error + Send + Sync + 'static>;
 pub type Result<T, E = StdError> = ::std::result::Result<T, E>;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Client {
-    client: FdwClient<tonic::transport::Channel>,
-    rt: Runtime,
+    connection: Connection,
+    retry: RetryPolicy,
+    codec: CodecOptions,
+    metadata: MetadataMap,
+    /// The table's `request_timeout`, set per call since connections are
+    /// shared by every table on the server.
+    timeout: Option<Duration>,
+    /// Table the calls are made for, to attribute statistics.
+    table: String,
+    /// Endpoint every call goes to, for sharded tables.
+    shard: Option<usize>,
 }
 
 impl Client {
-    pub fn connect<D>(dst: D) -> Result<Self, tonic::transport::Error>
+    pub fn new(
+        connection: Connection,
+        retry: RetryPolicy,
+        codec: CodecOptions,
+        metadata: MetadataMap,
+        timeout: Option<Duration>,
+        table: String,
+    ) -> Self {
+        Self {
+            connection,
+            retry,
+            codec,
+            metadata,
+            timeout,
+            table,
+            shard: None,
+        }
+    }
+
+    /// The same client making its calls on endpoint `shard` only.
+    pub fn on_shard(&self, shard: usize) -> Self {
+        Self {
+            shard: Some(shard),
+            ..self.clone()
+        }
+    }
+
+    fn connect(&self) -> Result<Call, RemoteError> {
+        match self.shard {
+            Some(shard) => self.connection.call_on(shard),
+            None => self.connection.call(),
+        }
+    }
+
+    /// Wraps a message with the metadata sent on every call, and the
+    /// deadline the server should give up at.
+    fn request<T>(&self, message: T) -> tonic::Request<T> {
+        let mut request = tonic::Request::new(message);
+        *request.metadata_mut() = self.metadata.clone();
+        if let Some(timeout) = self.timeout {
+            request.set_timeout(timeout);
+        }
+
+        request
+    }
+
+    /// A client for the calls made for `table`, configured from its options.
+    pub fn for_table(options: &ForeignOptions, table: String) -> Self {
+        Self::new(
+            connection::get(options),
+            options.retry_policy(),
+            options.codec(),
+            metadata::build(options),
+            options.request_timeout(),
+            table,
+        )
+    }
+
+    /// A `pg.Fdw` client compressing and limiting messages as configured.
+    /// Compressed responses are always accepted.
+    fn fdw(&self, channel: Channel) -> FdwClient<Channel> {
+        let mut client = FdwClient::new(channel);
+        for encoding in ACCEPTED {
+            client = client.accept_compressed(encoding);
+        }
+        if let Some(encoding) = self.codec.compression {
+            client = client.send_compressed(encoding);
+        }
+        if let Some(limit) = self.codec.max_decoding_message_size {
+            client = client.max_decoding_message_size(limit);
+        }
+        if let Some(limit) = self.codec.max_encoding_message_size {
+            client = client.max_encoding_message_size(limit);
+        }
+
+        client
+    }
+
+    /// Makes a call, repeating it with exponential backoff while it fails
+    /// with a retryable status. Reads are always safe to repeat since their
+    /// rows are only used once the whole stream arrived; writes are repeated
+    /// only when the server says so. Failures to connect are retried too.
+    ///
+    /// Every attempt is counted in the statistics, the future gets its
+    /// [`Attempt`] to report the messages it receives.
+    fn call<T, F, Fut>(&self, rpc: &str, idempotent: bool, sent: Sent, f: F) -> T
+    where
+        F: Fn(Channel, Rc<Attempt>) -> Fut,
+        Fut: Future<Output = Result<T, tonic::Status>>,
+    {
+        self.call_from(1, rpc, idempotent, sent, f)
+    }
+
+    /// [`Client::call`] returning the error once retries are exhausted, for
+    /// optional calls a failure of which should not fail the query.
+    fn try_call<T, F, Fut>(
+        &self,
+        rpc: &str,
+        idempotent: bool,
+        sent: Sent,
+        f: F,
+    ) -> Result<T, RemoteError>
     where
-        D: std::convert::TryInto<tonic::transport::Endpoint>,
-        D::Error: Into<StdError>,
+        F: Fn(Channel, Rc<Attempt>) -> Fut,
+        Fut: Future<Output = Result<T, tonic::Status>>,
     {
-        let rt = Builder::new_multi_thread().enable_all().build().unwrap();
-        let client = rt.block_on(FdwClient::connect(dst))?;
+        self.try_call_from(1, rpc, idempotent, sent, f)
+    }
 
-        Ok(Self { rt, client })
+    /// [`Client::call`] for a call whose earlier attempts were made elsewhere.
+    fn call_from<T, F, Fut>(&self, attempt: u32, rpc: &str, idempotent: bool, sent: Sent, f: F) -> T
+    where
+        F: Fn(Channel, Rc<Attempt>) -> Fut,
+        Fut: Future<Output = Result<T, tonic::Status>>,
+    {
+        self.try_call_from(attempt, rpc, idempotent, sent, f)
+            .unwrap_or_else(|e| e.raise())
     }
 
-    pub fn execute(&mut self, request: impl tonic::IntoRequest<ExecuteRequest>) -> Vec<ResultSet> {
-        let mut stream = self
-            .rt
-            .block_on(self.client.execute(request))
-            .unwrap()
-            .into_inner();
-        let mut v = Vec::new();
-        while let Some(msg) = self.rt.block_on(stream.message()).unwrap() {
-            v.push(msg);
+    fn try_call_from<T, F, Fut>(
+        &self,
+        mut attempt: u32,
+        rpc: &str,
+        idempotent: bool,
+        sent: Sent,
+        f: F,
+    ) -> Result<T, RemoteError>
+    where
+        F: Fn(Channel, Rc<Attempt>) -> Fut,
+        Fut: Future<Output = Result<T, tonic::Status>>,
+    {
+        let server = self.connection.server_name();
+        loop {
+            let stats = Rc::new(Attempt::start(sent));
+            let call = match self.connect() {
+                Ok(call) => call,
+                Err(e) => {
+                    stats.finish(server, &self.table, rpc, Code::Unavailable, None);
+                    if attempt < self.retry.max_attempts {
+                        self.backoff(attempt);
+                        attempt += 1;
+                        continue;
+                    }
+                    return Err(e);
+                }
+            };
+
+            let result = block_on(within(self.timeout, f(call.channel.clone(), stats.clone())));
+            let code = result
+                .as_ref()
+                .map_or_else(|status| status.code(), |_| Code::Ok);
+            stats.finish(server, &self.table, rpc, code, call.connect_time);
+            let status = match result {
+                Ok(value) => return Ok(value),
+                Err(status) => status,
+            };
+            call.failed(&status);
+
+            let retry = idempotent || error::is_idempotent(&status);
+            if retry && self.retry.should_retry(attempt, status.code()) {
+                drop(call);
+                self.backoff(attempt);
+                attempt += 1;
+                continue;
+            }
+
+            return Err(RemoteError::from_status(&status, call.endpoint(), rpc));
         }
+    }
 
-        v
+    fn backoff(&self, attempt: u32) {
+        block_on(tokio::time::sleep(self.retry.backoff(attempt)));
+        check_for_interrupts!();
     }
 
-    pub fn insert(&mut self, request: impl tonic::IntoRequest<InsertRequest>) -> () {
-        let mut stream = self
-            .rt
-            .block_on(self.client.insert(request))
-            .unwrap()
-            .into_inner();
+    pub fn execute(&self, request: ExecuteRequest) -> Vec<ResultSet> {
+        self.execute_from(1, request)
+    }
 
-        while let Some(msg) = self.rt.block_on(stream.message()).unwrap() {
-            warning!("{:?}", msg)
-        }
+    fn execute_from(&self, attempt: u32, request: ExecuteRequest) -> Vec<ResultSet> {
+        let sent = Sent {
+            rows: 0,
+            bytes: request.encoded_len(),
+        };
+        self.call_from(attempt, EXECUTE, true, sent, |channel, stats| {
+            let request = self.request(request.clone());
+            async move {
+                let mut client = self.fdw(channel);
+                collect(client.execute(request).await?.into_inner(), stats).await
+            }
+        })
     }
 
-    pub fn update(&mut self, request: impl tonic::IntoRequest<UpdateRequest>) -> () {
-        let mut stream = self
-            .rt
-            .block_on(self.client.update(request))
-            .unwrap()
-            .into_inner();
+    /// Starts an `Execute` call in the background, for
+    /// [`Client::finish_execute`] to pick up. `None` if no endpoint could be
+    /// reached, the scan then calls as usual once it runs. Notices can only
+    /// be reported from the backend's own thread, so they wait until then.
+    pub fn start_execute(&self, request: ExecuteRequest) -> Option<PendingExecute> {
+        let stats = Attempt::start(Sent {
+            rows: 0,
+            bytes: request.encoded_len(),
+        });
+        let call = self.connect().ok()?;
+        let mut client = self.fdw(call.channel.clone());
+        let message = self.request(request.clone());
+        let timeout = self.timeout;
+        let handle = connection::spawn(async move {
+            let mut messages = Vec::new();
+            let mut first_row = None;
+            let result = within(timeout, async {
+                let mut stream = client.execute(message).await?.into_inner();
+                while let Some(message) = stream.message().await? {
+                    first_row.get_or_insert_with(Instant::now);
+                    messages.push(message);
+                }
+
+                Ok(())
+            })
+            .await;
 
-        while let Some(msg) = self.rt.block_on(stream.message()).unwrap() {
-            warning!("{:?}", msg)
+            (messages, first_row, result)
+        });
+
+        Some(PendingExecute {
+            request,
+            call,
+            stats,
+            handle,
+        })
+    }
+
+    /// Waits for a call started by [`Client::start_execute`]. If it failed,
+    /// it counts as the first attempt and retries carry on as usual.
+    pub fn finish_execute(&self, pending: PendingExecute) -> Vec<ResultSet> {
+        let PendingExecute {
+            request,
+            call,
+            stats,
+            handle,
+        } = pending;
+        let (messages, first_row, result) = block_on(handle).unwrap_or_else(|e| {
+            let status = tonic::Status::internal(format!("scan failed: {}", e));
+            (Vec::new(), None, Err(status))
+        });
+
+        if let Some(first_row) = first_row {
+            stats.first_row_at(first_row);
+        }
+        for message in &messages {
+            stats.received(message.encoded_len());
+        }
+        let code = result
+            .as_ref()
+            .map_or_else(|status| status.code(), |_| Code::Ok);
+        let server = self.connection.server_name();
+        stats.finish(server, &self.table, EXECUTE, code, call.connect_time);
+
+        // Reported even when the call failed, the rows are dropped then.
+        let rows: Vec<ResultSet> = messages.into_iter().filter_map(Row::into_row).collect();
+        let status = match result {
+            Ok(()) => return rows,
+            Err(status) => status,
+        };
+        call.failed(&status);
+        if !self.retry.should_retry(1, status.code()) {
+            RemoteError::from_status(&status, call.endpoint(), EXECUTE).raise()
         }
+
+        drop(call);
+        self.backoff(1);
+        self.execute_from(2, request)
+    }
+
+    /// Scans `shards` concurrently, each on its own endpoint, and returns
+    /// their rows one shard after the other.
+    pub fn execute_shards(&self, shards: &[usize], request: ExecuteRequest) -> Vec<ResultSet> {
+        let clients: Vec<Client> = shards.iter().map(|shard| self.on_shard(*shard)).collect();
+        let pending: Vec<Option<PendingExecute>> = clients
+            .iter()
+            .map(|client| client.start_execute(request.clone()))
+            .collect();
+
+        clients
+            .iter()
+            .zip(pending)
+            .flat_map(|(client, pending)| match pending {
+                Some(pending) => client.finish_execute(pending),
+                None => client.execute(request.clone()),
+            })
+            .collect()
+    }
+
+    pub fn insert(&self, request: InsertRequest) {
+        let sent = Sent {
+            rows: 1,
+            bytes: request.encoded_len(),
+        };
+        self.call("pg.Fdw/Insert", false, sent, |channel, stats| {
+            let request = self.request(request.clone());
+            async move {
+                let mut client = self.fdw(channel);
+                collect(client.insert(request).await?.into_inner(), stats).await
+            }
+        });
+    }
+
+    pub fn update(&self, request: UpdateRequest) {
+        let sent = Sent {
+            rows: 1,
+            bytes: request.encoded_len(),
+        };
+        self.call("pg.Fdw/Update", false, sent, |channel, stats| {
+            let request = self.request(request.clone());
+            async move {
+                let mut client = self.fdw(channel);
+                collect(client.update(request).await?.into_inner(), stats).await
+            }
+        });
+    }
+
+    pub fn delete(&self, request: DeleteRequest) {
+        let sent = Sent {
+            rows: 1,
+            bytes: request.encoded_len(),
+        };
+        self.call("pg.Fdw/Delete", false, sent, |channel, stats| {
+            let request = self.request(request.clone());
+            async move {
+                let mut client = self.fdw(channel);
+                collect(client.delete(request).await?.into_inner(), stats).await
+            }
+        });
+    }
+
+    /// Asks the server how it would run `request`, `None` when it does not
+    /// implement the optional `Explain` RPC.
+    pub fn explain(&self, request: ExecuteRequest) -> Result<Option<ExplainResponse>, RemoteError> {
+        let sent = Sent {
+            rows: 0,
+            bytes: request.encoded_len(),
+        };
+        self.try_call("pg.Fdw/Explain", true, sent, |channel, _| {
+            let request = self.request(request.clone());
+            async move {
+                match self.fdw(channel).explain(request).await {
+                    Ok(response) => Ok(Some(response.into_inner())),
+                    Err(status) if status.code() == Code::Unimplemented => Ok(None),
+                    Err(status) => Err(status),
+                }
+            }
+        })
+    }
+
+    /// Asks the server to split the scan of `request` into partitions, none
+    /// when it does not implement the optional `PlanPartitions` RPC.
+    pub fn plan_partitions(&self, request: ExecuteRequest) -> Result<Vec<Partition>, RemoteError> {
+        let sent = Sent {
+            rows: 0,
+            bytes: request.encoded_len(),
+        };
+        self.try_call("pg.Fdw/PlanPartitions", true, sent, |channel, _| {
+            let request = self.request(request.clone());
+            async move {
+                match self.fdw(channel).plan_partitions(request).await {
+                    Ok(response) => Ok(response.into_inner().partitions),
+                    Err(status) if status.code() == Code::Unimplemented => Ok(Vec::new()),
+                    Err(status) => Err(status),
+                }
+            }
+        })
+    }
+
+    /// Scans by calling `method` on a service other than `pg.Fdw`, mapping
+    /// response fields to `columns` by name. The method is resolved on first
+    /// use, from the server's `descriptor_set` if it has one and through
+    /// server reflection otherwise.
+    pub fn call_method(
+        &self,
+        method: &str,
+        request: Option<&str>,
+        columns: &[String],
+    ) -> Vec<ResultSet> {
+        let descriptor = match self.connection.method(method) {
+            Some(descriptor) => descriptor,
+            None => {
+                let descriptor = match self.connection.descriptor_set() {
+                    Some(path) => reflection::load_descriptor_set(path)
+                        .and_then(|pool| reflection::find_method(&pool, method))
+                        .unwrap_or_else(|status| {
+                            error::raise(error::sqlstate(status.code()), status.message())
+                        }),
+                    None => self.call(reflection::RPC, true, Sent::default(), |channel, _| {
+                        reflection::resolve(channel, method, &self.metadata)
+                    }),
+                };
+                self.connection.cache_method(method, descriptor.clone());
+                descriptor
+            }
+        };
+        let request = dynamic::request(&descriptor, request).unwrap_or_else(|status| {
+            error::raise(error::sqlstate(status.code()), status.message())
+        });
+
+        let sent = Sent {
+            rows: 0,
+            bytes: request.encoded_len(),
+        };
+        let messages = self.call(method, true, sent, |channel, stats| {
+            let request = self.request(request.clone());
+            let descriptor = &descriptor;
+            async move {
+                collect(
+                    dynamic::call(channel, descriptor, request, &self.codec).await?,
+                    stats,
+                )
+                .await
+            }
+        });
+        messages
+            .iter()
+            .map(|message| ResultSet {
+                values: dynamic::row(message, columns),
+                notice: None,
+            })
+            .collect()
+    }
+}
+
+/// An `Execute` call running in the background, see
+/// [`Client::start_execute`].
+pub struct PendingExecute {
+    request: ExecuteRequest,
+    call: Call,
+    stats: Attempt,
+    /// The messages that arrived, when the first did, and how the call ended.
+    handle: JoinHandle<(Vec<ResultSet>, Option<Instant>, Result<(), tonic::Status>)>,
+}
+
+impl PendingExecute {
+    pub fn request(&self) -> &ExecuteRequest {
+        &self.request
     }
 
-    pub fn delete(&mut self, request: impl tonic::IntoRequest<DeleteRequest>) -> () {
-        let mut stream = self
-            .rt
-            .block_on(self.client.delete(request))
-            .unwrap()
-            .into_inner();
+    /// Gives up on the call, for scans that never ran.
+    pub fn cancel(self) {
+        self.handle.abort();
+    }
+}
+
+/// Messages of a result stream, some of which may be notices instead of
+/// rows.
+trait Row: Message + Sized {
+    /// Reports the notice this message carries, or returns it as a row.
+    fn into_row(self) -> Option<Self> {
+        Some(self)
+    }
+}
 
-        while let Some(msg) = self.rt.block_on(stream.message()).unwrap() {
-            warning!("{:?}", msg)
+impl Row for ResultSet {
+    fn into_row(self) -> Option<Self> {
+        match &self.notice {
+            Some(notice) => {
+                report_notice(notice);
+                None
+            }
+            None => Some(self),
         }
     }
 }
+
+impl Row for prost_reflect::DynamicMessage {}
+
+/// Drains a result stream, reporting notices as they arrive. A failure part
+/// way through fails the whole call, so no row from a failed attempt is ever
+/// returned, while the notices sent before the failure have been reported.
+async fn collect<M: Row>(
+    mut stream: Streaming<M>,
+    stats: Rc<Attempt>,
+) -> Result<Vec<M>, tonic::Status> {
+    let mut rows = Vec::new();
+    while let Some(message) = stream.message().await? {
+        stats.received(message.encoded_len());
+        rows.extend(message.into_row());
+    }
+
+    Ok(rows)
+}
+
+/// Fails `call` with `DEADLINE_EXCEEDED` once `timeout` passed, whether or
+/// not the server honours the deadline it was sent.
+async fn within<T, Fut>(timeout: Option<Duration>, call: Fut) -> Result<T, tonic::Status>
+where
+    Fut: Future<Output = Result<T, tonic::Status>>,
+{
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, call)
+            .await
+            .unwrap_or_else(|_| {
+                Err(tonic::Status::deadline_exceeded(format!(
+                    "no response within {} ms",
+                    timeout.as_millis()
+                )))
+            }),
+        None => call.await,
+    }
+}
@@ -0,0 +1,16 @@
+use tonic::codec::CompressionEncoding;
+
+/// Encodings accepted in responses, whichever `compression` is configured.
+pub const ACCEPTED: [CompressionEncoding; 2] =
+    [CompressionEncoding::Gzip, CompressionEncoding::Zstd];
+
+/// How messages are encoded on every call made for a server.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CodecOptions {
+    /// Encoding for request messages, `None` sends them uncompressed.
+    pub compression: Option<CompressionEncoding>,
+    /// Largest response message accepted, tonic's 4 MB by default.
+    pub max_decoding_message_size: Option<usize>,
+    /// Largest request message sent, unlimited by default.
+    pub max_encoding_message_size: Option<usize>,
+}
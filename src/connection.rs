@@ -0,0 +1,514 @@
+use crate::error::{self, RemoteError};
+use crate::health;
+use crate::options::{self, ForeignOptions};
+use crate::tls;
+use crate::uds;
+use pgx::*;
+use prost_reflect::MethodDescriptor;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::future::Future;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use tokio::runtime::{Builder, Runtime};
+use tonic::transport::{Channel, Endpoint};
+
+thread_local! {
+    /// A backend runs queries one at a time, so a single worker thread is
+    /// enough to drive every connection's HTTP/2 machinery.
+    static RUNTIME: Runtime = Builder::new_multi_thread()
+        .worker_threads(1)
+        .thread_name("grpc_fdw")
+        .enable_all()
+        .build()
+        .unwrap();
+
+    static CONNECTIONS: RefCell<HashMap<ConnectionKey, Connection>> = RefCell::new(HashMap::new());
+
+    static CALLBACKS_REGISTERED: RefCell<bool> = RefCell::new(false);
+}
+
+/// Runs a future to completion on the backend's runtime.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    RUNTIME.with(|rt| rt.block_on(future))
+}
+
+/// Runs a future in the background on the backend's runtime. It makes
+/// progress while the backend does other work, not only inside `block_on`.
+pub fn spawn<F>(future: F) -> tokio::task::JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    RUNTIME.with(|rt| rt.spawn(future))
+}
+
+/// Connections are shared by everything using the same server as the same
+/// user mapping, like postgres_fdw does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct ConnectionKey {
+    server: pg_sys::Oid,
+    user_mapping: pg_sys::Oid,
+}
+
+/// How endpoints are chosen for each call when a server has several.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Policy {
+    /// Use the first healthy endpoint, failing over to the next ones.
+    PickFirst,
+    /// Rotate calls over all healthy endpoints.
+    RoundRobin,
+}
+
+pub const POLICIES: &[&str] = &["pick_first", "round_robin"];
+
+#[derive(Debug, Clone)]
+pub struct Connection {
+    backends: Rc<Vec<Backend>>,
+    policy: Policy,
+    next: Rc<Cell<usize>>,
+    connect_timeout: Option<Duration>,
+    descriptor_set: Option<String>,
+    /// Descriptors resolved for `method` tables, dropped with the connection
+    /// so a changed server is asked again.
+    methods: Rc<RefCell<HashMap<String, MethodDescriptor>>>,
+    server_name: String,
+    user_name: String,
+    server_hash: u32,
+    user_mapping_hash: u32,
+}
+
+/// One endpoint of a server, connected on first use.
+#[derive(Debug)]
+struct Backend {
+    uri: String,
+    endpoint: Endpoint,
+    insecure: Option<tls::InsecureTls>,
+    /// Also set on `endpoint`, but the insecure TLS connector dials itself.
+    tcp_nodelay: bool,
+    channel: RefCell<Option<Channel>>,
+    /// Set until the endpoint passes a health check, even once expired.
+    ejected_until: Cell<Option<Instant>>,
+    /// How long a failed endpoint is skipped before it is checked again.
+    ejection_time: Duration,
+    in_flight: Cell<i32>,
+    connected_at: Cell<Option<pg_sys::TimestampTz>>,
+    last_used: Cell<Option<pg_sys::TimestampTz>>,
+}
+
+impl Backend {
+    fn new(uri: &str, options: &ForeignOptions) -> Self {
+        let mut endpoint = match options::parse_uri(uri) {
+            Ok(endpoint) => endpoint,
+            Err(e) => error::raise(
+                PgSqlErrorCode::ERRCODE_FDW_INVALID_STRING_FORMAT,
+                &format!("invalid server URI: {}", e),
+            ),
+        };
+        let mut insecure = None;
+        if let Some(tls) = options.tls(uri) {
+            let configured = tls::configure(endpoint, &tls).unwrap_or_else(|e| {
+                error::raise(PgSqlErrorCode::ERRCODE_FDW_ERROR, &format!("TLS: {}", e))
+            });
+            endpoint = configured.0;
+            insecure = configured.1;
+        }
+        if let Some(interval) = options.keepalive_interval() {
+            endpoint = endpoint
+                .http2_keep_alive_interval(interval)
+                .keep_alive_while_idle(true);
+        }
+        if let Some(timeout) = options.keepalive_timeout() {
+            endpoint = endpoint.keep_alive_timeout(timeout);
+        }
+        let tcp_nodelay = options.tcp_nodelay();
+        endpoint = endpoint
+            .initial_stream_window_size(options.initial_stream_window_size())
+            .initial_connection_window_size(options.initial_connection_window_size())
+            .tcp_nodelay(tcp_nodelay);
+
+        Self {
+            uri: uri.to_string(),
+            endpoint,
+            insecure,
+            tcp_nodelay,
+            channel: RefCell::new(None),
+            ejected_until: Cell::new(None),
+            ejection_time: options.ejection_time(),
+            in_flight: Cell::new(0),
+            connected_at: Cell::new(None),
+            last_used: Cell::new(None),
+        }
+    }
+
+    fn is_ejected(&self, now: Instant) -> bool {
+        self.ejected_until.get().map_or(false, |until| until > now)
+    }
+
+    fn eject(&self) {
+        self.ejected_until
+            .set(Some(Instant::now() + self.ejection_time));
+        self.channel.borrow_mut().take();
+        self.connected_at.set(None);
+    }
+
+    /// The endpoint's channel, connecting first if needed, along with how
+    /// long connecting took.
+    fn channel(
+        &self,
+        timeout: Option<Duration>,
+    ) -> Result<(Channel, Option<Duration>), RemoteError> {
+        if let Some(channel) = self.channel.borrow().as_ref() {
+            return Ok((channel.clone(), None));
+        }
+
+        let started = Instant::now();
+        let uri = &self.uri;
+        let endpoint = &self.endpoint;
+        let channel = block_on(async {
+            let connect = async {
+                match (uds::socket_path(uri), &self.insecure) {
+                    (Some(path), _) => uds::connect(endpoint, path).await,
+                    (None, Some(insecure)) => {
+                        tls::connect_insecure(endpoint, insecure, self.tcp_nodelay).await
+                    }
+                    (None, None) => endpoint.connect().await,
+                }
+            };
+
+            match timeout {
+                Some(timeout) => tokio::time::timeout(timeout, connect)
+                    .await
+                    .map_err(|_| RemoteError::connect_timeout(timeout, uri))?,
+                None => connect.await,
+            }
+            .map_err(|e| RemoteError::from_transport(&e, uri))
+        })?;
+
+        self.connected_at
+            .set(Some(unsafe { pg_sys::GetCurrentTimestamp() }));
+        *self.channel.borrow_mut() = Some(channel.clone());
+
+        Ok((channel, Some(started.elapsed())))
+    }
+}
+
+impl Connection {
+    /// Picks an endpoint for a call, connecting to it if needed. Endpoints
+    /// that fail to connect are ejected and the next one is tried, ejected
+    /// ones must pass a health check before they are used again.
+    pub fn call(&self) -> Result<Call, RemoteError> {
+        let n = self.backends.len();
+        let start = match self.policy {
+            Policy::PickFirst => 0,
+            Policy::RoundRobin => {
+                let start = self.next.get();
+                self.next.set((start + 1) % n);
+                start
+            }
+        };
+        let order: Vec<usize> = (0..n).map(|i| (start + i) % n).collect();
+        let now = Instant::now();
+        let healthy: Vec<usize> = order
+            .iter()
+            .copied()
+            .filter(|i| !self.backends[*i].is_ejected(now))
+            .collect();
+        // With every endpoint ejected, trying them all beats failing outright.
+        let candidates = if healthy.is_empty() { order } else { healthy };
+
+        let mut last_error = None;
+        for index in candidates {
+            let backend = &self.backends[index];
+            let channel =
+                backend
+                    .channel(self.connect_timeout)
+                    .and_then(|(channel, connect_time)| {
+                        if backend.ejected_until.get().is_some() {
+                            let timeout = self.connect_timeout.unwrap_or(health::DEFAULT_TIMEOUT);
+                            health::check(channel.clone(), &backend.uri, timeout)?;
+                            backend.ejected_until.set(None);
+                        }
+                        Ok((channel, connect_time))
+                    });
+            match channel {
+                Ok((channel, connect_time)) => {
+                    return Ok(Call::new(
+                        self.backends.clone(),
+                        index,
+                        channel,
+                        connect_time,
+                    ))
+                }
+                Err(e) => {
+                    backend.eject();
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        Err(last_error.unwrap())
+    }
+}
+
+impl Connection {
+    pub fn server_name(&self) -> &str {
+        &self.server_name
+    }
+
+    pub fn descriptor_set(&self) -> Option<&str> {
+        self.descriptor_set.as_deref()
+    }
+
+    pub fn method(&self, name: &str) -> Option<MethodDescriptor> {
+        self.methods.borrow().get(name).cloned()
+    }
+
+    pub fn cache_method(&self, name: &str, method: MethodDescriptor) {
+        self.methods.borrow_mut().insert(name.to_string(), method);
+    }
+
+    /// Starts a call on endpoint `index`, for sharded tables whose rows each
+    /// live on one endpoint. There is nothing to fail over to.
+    pub fn call_on(&self, index: usize) -> Result<Call, RemoteError> {
+        let (channel, connect_time) = self.backends[index].channel(self.connect_timeout)?;
+
+        Ok(Call::new(
+            self.backends.clone(),
+            index,
+            channel,
+            connect_time,
+        ))
+    }
+
+    /// Starts a call on every endpoint in turn, regardless of the balancing
+    /// policy and of ejections, for checks that need to see all of them.
+    pub fn call_each(&self) -> Vec<(String, Result<Call, RemoteError>)> {
+        (0..self.backends.len())
+            .map(|index| {
+                let backend = &self.backends[index];
+                let call = backend
+                    .channel(self.connect_timeout)
+                    .map(|(channel, connect_time)| {
+                        Call::new(self.backends.clone(), index, channel, connect_time)
+                    });
+
+                (backend.uri.clone(), call)
+            })
+            .collect()
+    }
+}
+
+/// A call in flight on one endpoint, counted until dropped.
+pub struct Call {
+    pub channel: Channel,
+    /// How long connecting took, if the call had to connect.
+    pub connect_time: Option<Duration>,
+    backends: Rc<Vec<Backend>>,
+    index: usize,
+}
+
+impl Call {
+    fn new(
+        backends: Rc<Vec<Backend>>,
+        index: usize,
+        channel: Channel,
+        connect_time: Option<Duration>,
+    ) -> Self {
+        let backend = &backends[index];
+        backend.in_flight.set(backend.in_flight.get() + 1);
+        backend
+            .last_used
+            .set(Some(unsafe { pg_sys::GetCurrentTimestamp() }));
+
+        Self {
+            channel,
+            connect_time,
+            backends,
+            index,
+        }
+    }
+
+    pub fn endpoint(&self) -> &str {
+        &self.backends[self.index].uri
+    }
+
+    /// Ejects the endpoint if the failure means it is unreachable, so later
+    /// calls go elsewhere.
+    pub fn failed(&self, status: &tonic::Status) {
+        if status.code() == tonic::Code::Unavailable {
+            self.backends[self.index].eject();
+        }
+    }
+}
+
+impl Drop for Call {
+    fn drop(&mut self) {
+        let backend = &self.backends[self.index];
+        backend.in_flight.set(backend.in_flight.get() - 1);
+    }
+}
+
+/// Returns the cached connection for the table's server and user mapping,
+/// creating it if there is none. Endpoints are connected on first use.
+pub fn get(options: &ForeignOptions) -> Connection {
+    register_callbacks();
+
+    let key = ConnectionKey {
+        server: options.server_id,
+        user_mapping: options.user_mapping_id,
+    };
+    if let Some(connection) = CONNECTIONS.with(|c| c.borrow().get(&key).cloned()) {
+        return connection;
+    }
+
+    let connection = Connection {
+        backends: Rc::new(
+            options
+                .server_uris()
+                .iter()
+                .map(|uri| Backend::new(uri, options))
+                .collect(),
+        ),
+        policy: options.load_balancing(),
+        next: Rc::new(Cell::new(0)),
+        connect_timeout: options.connect_timeout(),
+        descriptor_set: options.server.get("descriptor_set").cloned(),
+        methods: Rc::new(RefCell::new(HashMap::new())),
+        server_name: options.server_name.clone(),
+        user_name: options.user_name.clone(),
+        server_hash: syscache_hash(
+            pg_sys::SysCacheIdentifier_FOREIGNSERVEROID,
+            options.server_id,
+        ),
+        user_mapping_hash: syscache_hash(
+            pg_sys::SysCacheIdentifier_USERMAPPINGOID,
+            options.user_mapping_id,
+        ),
+    };
+    CONNECTIONS.with(|c| c.borrow_mut().insert(key, connection.clone()));
+
+    connection
+}
+
+fn syscache_hash(cache: u32, oid: pg_sys::Oid) -> u32 {
+    unsafe { pg_sys::GetSysCacheHashValue(cache as i32, oid.into_datum().unwrap(), 0, 0, 0) }
+}
+
+fn register_callbacks() {
+    CALLBACKS_REGISTERED.with(|registered| {
+        if !*registered.borrow() {
+            unsafe {
+                pg_sys::CacheRegisterSyscacheCallback(
+                    pg_sys::SysCacheIdentifier_FOREIGNSERVEROID as i32,
+                    Some(invalidate),
+                    0,
+                );
+                pg_sys::CacheRegisterSyscacheCallback(
+                    pg_sys::SysCacheIdentifier_USERMAPPINGOID as i32,
+                    Some(invalidate),
+                    0,
+                );
+            }
+            *registered.borrow_mut() = true;
+        }
+    })
+}
+
+/// Drops connections whose server or user mapping changed, so the next scan
+/// reconnects with the new options. Scans still holding the old channel keep
+/// using it until they finish.
+#[pg_guard]
+unsafe extern "C" fn invalidate(_arg: pg_sys::Datum, cache: i32, hash: u32) {
+    CONNECTIONS.with(|c| {
+        c.borrow_mut().retain(|_, connection| {
+            let entry_hash = if cache == pg_sys::SysCacheIdentifier_FOREIGNSERVEROID as i32 {
+                connection.server_hash
+            } else {
+                connection.user_mapping_hash
+            };
+
+            hash != 0 && entry_hash != hash
+        })
+    })
+}
+
+fn timestamptz(ts: pg_sys::TimestampTz) -> TimestampWithTimeZone {
+    unsafe {
+        TimestampWithTimeZone::from_datum(ts as pg_sys::Datum, false, pg_sys::TIMESTAMPTZOID)
+            .unwrap()
+    }
+}
+
+/// Lists the gRPC connections cached by this backend, one row per endpoint.
+#[pg_extern]
+fn grpc_fdw_get_connections() -> impl std::iter::Iterator<
+    Item = (
+        name!(server_name, String),
+        name!(user_name, String),
+        name!(endpoint, String),
+        name!(state, String),
+        name!(in_flight, i32),
+        name!(created_at, Option<TimestampWithTimeZone>),
+        name!(last_used, Option<TimestampWithTimeZone>),
+    ),
+> {
+    let mut connections: Vec<Connection> =
+        CONNECTIONS.with(|c| c.borrow().values().cloned().collect());
+    connections.sort_by(|a, b| (&a.server_name, &a.user_name).cmp(&(&b.server_name, &b.user_name)));
+
+    let now = Instant::now();
+    let mut rows = Vec::new();
+    for connection in connections {
+        for backend in connection.backends.iter() {
+            let state = if backend.is_ejected(now) {
+                "ejected"
+            } else if backend.channel.borrow().is_none() {
+                "disconnected"
+            } else if backend.in_flight.get() > 0 {
+                "active"
+            } else {
+                "idle"
+            };
+
+            rows.push((
+                connection.server_name.clone(),
+                connection.user_name.clone(),
+                backend.uri.clone(),
+                state.to_string(),
+                backend.in_flight.get(),
+                backend.connected_at.get().map(timestamptz),
+                backend.last_used.get().map(timestamptz),
+            ));
+        }
+    }
+
+    rows.into_iter()
+}
+
+/// Drops the cached connections to `server`, so the next query reconnects.
+/// Calls already in flight finish on the old connection.
+#[pg_extern]
+fn grpc_fdw_disconnect(server: &str) -> bool {
+    let name = CString::new(server).unwrap();
+    let server_id = unsafe { (*pg_sys::GetForeignServerByName(name.as_ptr(), false)).serverid };
+
+    disconnect(|key| key.server == server_id)
+}
+
+/// Drops every connection cached by this backend.
+#[pg_extern]
+fn grpc_fdw_disconnect_all() -> bool {
+    disconnect(|_| true)
+}
+
+fn disconnect(matches: impl Fn(&ConnectionKey) -> bool) -> bool {
+    CONNECTIONS.with(|c| {
+        let mut connections = c.borrow_mut();
+        let before = connections.len();
+        connections.retain(|key, _| !matches(key));
+
+        connections.len() != before
+    })
+}
@@ -0,0 +1,223 @@
+use crate::codec::{CodecOptions, ACCEPTED};
+use prost::Message;
+use prost_reflect::{
+    DynamicMessage, Kind, MapKey, MessageDescriptor, MethodDescriptor, ReflectMessage, Value,
+};
+use prost_types::value::Kind as ValueKind;
+use tonic::codec::{Codec, DecodeBuf, Decoder, EncodeBuf, Encoder, Streaming};
+use tonic::transport::Channel;
+use tonic::Status;
+
+/// Calls a method known only from its descriptor, returning the stream of
+/// response messages. Unary methods are called the same way, their single
+/// response looks no different on the wire.
+pub async fn call(
+    channel: Channel,
+    method: &MethodDescriptor,
+    request: tonic::Request<DynamicMessage>,
+    codec: &CodecOptions,
+) -> Result<Streaming<DynamicMessage>, Status> {
+    if method.is_client_streaming() {
+        return Err(Status::unimplemented(format!(
+            "client streaming method \"{}\" cannot be used as a table",
+            method.full_name()
+        )));
+    }
+
+    let mut grpc = tonic::client::Grpc::new(channel);
+    for encoding in ACCEPTED {
+        grpc = grpc.accept_compressed(encoding);
+    }
+    if let Some(encoding) = codec.compression {
+        grpc = grpc.send_compressed(encoding);
+    }
+    if let Some(limit) = codec.max_decoding_message_size {
+        grpc = grpc.max_decoding_message_size(limit);
+    }
+    if let Some(limit) = codec.max_encoding_message_size {
+        grpc = grpc.max_encoding_message_size(limit);
+    }
+    grpc.ready()
+        .await
+        .map_err(|e| Status::unavailable(format!("service was not ready: {}", e)))?;
+
+    let path = format!("/{}/{}", method.parent_service().full_name(), method.name());
+    let path = http::uri::PathAndQuery::from_maybe_shared(path)
+        .map_err(|e| Status::invalid_argument(e.to_string()))?;
+    let codec = DynamicCodec(method.output());
+
+    Ok(grpc
+        .server_streaming(request, path, codec)
+        .await?
+        .into_inner())
+}
+
+/// Builds the request message from its JSON form, or an empty message.
+pub fn request(method: &MethodDescriptor, json: Option<&str>) -> Result<DynamicMessage, Status> {
+    let json = match json {
+        Some(json) => json,
+        None => return Ok(DynamicMessage::new(method.input())),
+    };
+
+    let mut deserializer = serde_json::Deserializer::from_str(json);
+    let message = DynamicMessage::deserialize(method.input(), &mut deserializer)
+        .and_then(|message| deserializer.end().map(|_| message))
+        .map_err(|e| {
+            Status::invalid_argument(format!(
+                "request is not a valid {}: {}",
+                method.input().full_name(),
+                e
+            ))
+        })?;
+
+    Ok(message)
+}
+
+/// Maps the fields of a response message onto columns by name. Columns
+/// without a matching field, and fields with presence that are not set,
+/// become NULL.
+pub fn row(message: &DynamicMessage, columns: &[String]) -> Vec<prost_types::Value> {
+    let descriptor = message.descriptor();
+    columns
+        .iter()
+        .map(|column| {
+            let field = descriptor.get_field_by_name(column).or_else(|| {
+                descriptor
+                    .fields()
+                    .find(|field| field.json_name() == column)
+            });
+            let kind = match field {
+                Some(field) if !field.supports_presence() || message.has_field(&field) => {
+                    convert(&field.kind(), &message.get_field(&field))
+                }
+                _ => ValueKind::NullValue(0),
+            };
+
+            prost_types::Value { kind: Some(kind) }
+        })
+        .collect()
+}
+
+/// Converts a field value, with `kind` the type of the field, or of its
+/// elements for repeated fields.
+fn convert(kind: &Kind, value: &Value) -> ValueKind {
+    match value {
+        Value::Bool(b) => ValueKind::BoolValue(*b),
+        Value::I32(n) => ValueKind::NumberValue(*n as f64),
+        Value::I64(n) => ValueKind::NumberValue(*n as f64),
+        Value::U32(n) => ValueKind::NumberValue(*n as f64),
+        Value::U64(n) => ValueKind::NumberValue(*n as f64),
+        Value::F32(n) => ValueKind::NumberValue(*n as f64),
+        Value::F64(n) => ValueKind::NumberValue(*n),
+        Value::String(s) => ValueKind::StringValue(s.clone()),
+        Value::Bytes(bytes) => ValueKind::StringValue(bytea(bytes)),
+        Value::EnumNumber(number) => match kind.as_enum().and_then(|e| e.get_value(*number)) {
+            Some(value) => ValueKind::StringValue(value.name().to_string()),
+            None => ValueKind::NumberValue(*number as f64),
+        },
+        Value::Message(message) => ValueKind::StructValue(structure(message)),
+        Value::List(values) => ValueKind::ListValue(prost_types::ListValue {
+            values: values
+                .iter()
+                .map(|value| prost_types::Value {
+                    kind: Some(convert(kind, value)),
+                })
+                .collect(),
+        }),
+        Value::Map(map) => {
+            let value_kind = match kind.as_message() {
+                Some(entry) => entry.map_entry_value_field().kind(),
+                None => kind.clone(),
+            };
+
+            ValueKind::StructValue(prost_types::Struct {
+                fields: map
+                    .iter()
+                    .map(|(key, value)| {
+                        let value = prost_types::Value {
+                            kind: Some(convert(&value_kind, value)),
+                        };
+                        (map_key(key), value)
+                    })
+                    .collect(),
+            })
+        }
+    }
+}
+
+/// Nested messages become objects holding their set fields.
+fn structure(message: &DynamicMessage) -> prost_types::Struct {
+    prost_types::Struct {
+        fields: message
+            .fields()
+            .map(|(field, value)| {
+                let value = prost_types::Value {
+                    kind: Some(convert(&field.kind(), value)),
+                };
+                (field.name().to_string(), value)
+            })
+            .collect(),
+    }
+}
+
+fn map_key(key: &MapKey) -> String {
+    match key {
+        MapKey::Bool(b) => b.to_string(),
+        MapKey::I32(n) => n.to_string(),
+        MapKey::I64(n) => n.to_string(),
+        MapKey::U32(n) => n.to_string(),
+        MapKey::U64(n) => n.to_string(),
+        MapKey::String(s) => s.clone(),
+    }
+}
+
+/// Formats bytes the way `bytea` prints them.
+fn bytea(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(2 + bytes.len() * 2);
+    s.push_str("\\x");
+    for byte in bytes {
+        s.push_str(&format!("{:02x}", byte));
+    }
+
+    s
+}
+
+/// Encodes requests and decodes responses of a method described at runtime.
+#[derive(Debug, Clone)]
+struct DynamicCodec(MessageDescriptor);
+
+impl Codec for DynamicCodec {
+    type Encode = DynamicMessage;
+    type Decode = DynamicMessage;
+    type Encoder = DynamicCodec;
+    type Decoder = DynamicCodec;
+
+    fn encoder(&mut self) -> Self::Encoder {
+        self.clone()
+    }
+
+    fn decoder(&mut self) -> Self::Decoder {
+        self.clone()
+    }
+}
+
+impl Encoder for DynamicCodec {
+    type Item = DynamicMessage;
+    type Error = Status;
+
+    fn encode(&mut self, item: DynamicMessage, dst: &mut EncodeBuf<'_>) -> Result<(), Status> {
+        item.encode(dst)
+            .map_err(|e| Status::internal(format!("could not encode request: {}", e)))
+    }
+}
+
+impl Decoder for DynamicCodec {
+    type Item = DynamicMessage;
+    type Error = Status;
+
+    fn decode(&mut self, src: &mut DecodeBuf<'_>) -> Result<Option<DynamicMessage>, Status> {
+        DynamicMessage::decode(self.0.clone(), src)
+            .map(Some)
+            .map_err(|e| Status::internal(format!("could not decode response: {}", e)))
+    }
+}
@@ -0,0 +1,289 @@
+use crate::client::pg::{ErrorDetails, Notice, NoticeLevel};
+use pgx::*;
+use prost::Message;
+use std::ffi::CString;
+use tonic::Code;
+
+mod rpc {
+    tonic::include_proto!("google.rpc");
+}
+
+const ERROR_DETAILS_TYPE: &str = "pg.ErrorDetails";
+
+/// A failure talking to the foreign server, raised as a Postgres `ERROR`.
+pub struct RemoteError {
+    pub sqlstate: i32,
+    pub message: String,
+    pub context: String,
+    pub details: Option<ErrorDetails>,
+}
+
+impl RemoteError {
+    pub fn from_status(status: &tonic::Status, endpoint: &str, rpc: &str) -> Self {
+        let message = if status.message().is_empty() {
+            format!("gRPC call failed: {}", status.code().description())
+        } else {
+            status.message().to_string()
+        };
+
+        let details = error_details(status.details());
+        let sqlstate = details
+            .as_ref()
+            .and_then(|d| make_sqlstate(&d.sqlstate))
+            .unwrap_or(sqlstate(status.code()) as i32);
+
+        Self {
+            sqlstate,
+            message,
+            context: format!("gRPC call {} on \"{}\"", rpc, endpoint),
+            details,
+        }
+    }
+
+    pub fn from_transport(error: &tonic::transport::Error, endpoint: &str) -> Self {
+        Self {
+            sqlstate: PgSqlErrorCode::ERRCODE_SQLCLIENT_UNABLE_TO_ESTABLISH_SQLCONNECTION as i32,
+            message: format!("could not connect to gRPC server: {}", error),
+            context: format!("gRPC server \"{}\"", endpoint),
+            details: None,
+        }
+    }
+
+    pub fn connect_timeout(timeout: std::time::Duration, endpoint: &str) -> Self {
+        Self {
+            sqlstate: PgSqlErrorCode::ERRCODE_SQLCLIENT_UNABLE_TO_ESTABLISH_SQLCONNECTION as i32,
+            message: format!(
+                "could not connect to gRPC server within {} seconds",
+                timeout.as_secs()
+            ),
+            context: format!("gRPC server \"{}\"", endpoint),
+            details: None,
+        }
+    }
+
+    pub fn unhealthy(status: &str, endpoint: &str) -> Self {
+        Self {
+            sqlstate: PgSqlErrorCode::ERRCODE_SQLCLIENT_UNABLE_TO_ESTABLISH_SQLCONNECTION as i32,
+            message: format!("gRPC server is not healthy: {}", status),
+            context: format!("gRPC server \"{}\"", endpoint),
+            details: None,
+        }
+    }
+
+    pub fn raise(self) -> ! {
+        let message = cstring(&self.message);
+        let context = cstring(&self.context);
+
+        unsafe {
+            if errstart(pg_sys::ERROR) {
+                pg_sys::errcode(self.sqlstate);
+                pg_sys::errmsg(FORMAT.as_ptr() as *const _, message.as_ptr());
+                if let Some(details) = &self.details {
+                    report_details(details);
+                }
+                pg_sys::errcontext_msg(FORMAT.as_ptr() as *const _, context.as_ptr());
+                errfinish();
+            }
+        }
+
+        unreachable!("ereport(ERROR) returned")
+    }
+}
+
+/// `ereport(ERROR)` for failures detected locally, before any call is made.
+pub fn raise(sqlstate: PgSqlErrorCode, message: &str) -> ! {
+    ereport!(PgLogLevel::ERROR, sqlstate, message);
+    unreachable!("ereport(ERROR) returned")
+}
+
+/// Emits a server-sent notice at the matching elog level.
+pub fn report_notice(notice: &Notice) {
+    let level = match NoticeLevel::try_from(notice.level).ok() {
+        Some(NoticeLevel::Debug) => pg_sys::DEBUG1,
+        Some(NoticeLevel::Log) => pg_sys::LOG,
+        Some(NoticeLevel::Warning) => pg_sys::WARNING,
+        Some(NoticeLevel::Notice) | None => pg_sys::NOTICE,
+    };
+    let message = cstring(&notice.message);
+
+    unsafe {
+        if errstart(level) {
+            pg_sys::errmsg(FORMAT.as_ptr() as *const _, message.as_ptr());
+            if !notice.detail.is_empty() {
+                let detail = cstring(&notice.detail);
+                pg_sys::errdetail(FORMAT.as_ptr() as *const _, detail.as_ptr());
+            }
+            errfinish();
+        }
+    }
+}
+
+/// Like [`raise`], with a hint telling the user how to fix the problem.
+pub fn raise_with_hint(sqlstate: PgSqlErrorCode, message: &str, hint: &str) -> ! {
+    let message = cstring(message);
+    let hint = cstring(hint);
+
+    unsafe {
+        if errstart(pg_sys::ERROR) {
+            pg_sys::errcode(sqlstate as i32);
+            pg_sys::errmsg(FORMAT.as_ptr() as *const _, message.as_ptr());
+            pg_sys::errhint(FORMAT.as_ptr() as *const _, hint.as_ptr());
+            errfinish();
+        }
+    }
+
+    unreachable!("ereport(ERROR) returned")
+}
+
+/// Runs `f` with `context` added as a `CONTEXT` line to any error raised
+/// meanwhile, by us or by Postgres.
+pub fn with_context<T>(context: &str, f: impl FnOnce() -> T) -> T {
+    let context = cstring(context);
+    let previous = unsafe { pg_sys::error_context_stack };
+    let mut callback = pg_sys::ErrorContextCallback {
+        previous,
+        callback: Some(report_context),
+        arg: context.as_ptr() as *mut std::os::raw::c_void,
+    };
+
+    unsafe { pg_sys::error_context_stack = &mut callback };
+    // Also pops the callback when the error unwinds through here.
+    let _restore = RestoreContext(previous);
+
+    f()
+}
+
+struct RestoreContext(*mut pg_sys::ErrorContextCallback);
+
+impl Drop for RestoreContext {
+    fn drop(&mut self) {
+        unsafe { pg_sys::error_context_stack = self.0 };
+    }
+}
+
+#[pg_guard]
+unsafe extern "C" fn report_context(arg: *mut std::os::raw::c_void) {
+    pg_sys::errcontext_msg(
+        FORMAT.as_ptr() as *const _,
+        arg as *const std::os::raw::c_char,
+    );
+}
+
+const FORMAT: &[u8] = b"%s\0";
+
+unsafe fn report_details(details: &ErrorDetails) {
+    if !details.detail.is_empty() {
+        let detail = cstring(&details.detail);
+        pg_sys::errdetail(FORMAT.as_ptr() as *const _, detail.as_ptr());
+    }
+    if !details.hint.is_empty() {
+        let hint = cstring(&details.hint);
+        pg_sys::errhint(FORMAT.as_ptr() as *const _, hint.as_ptr());
+    }
+
+    let fields = [
+        (pg_sys::PG_DIAG_TABLE_NAME, &details.table),
+        (pg_sys::PG_DIAG_COLUMN_NAME, &details.column),
+        (pg_sys::PG_DIAG_CONSTRAINT_NAME, &details.constraint),
+    ];
+    for (field, value) in fields.iter() {
+        if !value.is_empty() {
+            // err_generic_string copies the string into the error context.
+            let value = cstring(value);
+            pg_sys::err_generic_string(*field as i32, value.as_ptr());
+        }
+    }
+}
+
+/// Whether the server marked the failed call as safe to repeat.
+pub fn is_idempotent(status: &tonic::Status) -> bool {
+    error_details(status.details()).map_or(false, |details| details.idempotent)
+}
+
+/// Extracts our `pg.ErrorDetails` from the `google.rpc.Status` carried in
+/// `grpc-status-details-bin`, if the server sent one.
+fn error_details(bytes: &[u8]) -> Option<ErrorDetails> {
+    if bytes.is_empty() {
+        return None;
+    }
+
+    let status = rpc::Status::decode(bytes).ok()?;
+    status
+        .details
+        .iter()
+        .find(|any| any.type_url.rsplit('/').next() == Some(ERROR_DETAILS_TYPE))
+        .and_then(|any| ErrorDetails::decode(&any.value[..]).ok())
+}
+
+/// Encodes a five character SQLSTATE the way `MAKE_SQLSTATE` does.
+pub(crate) fn make_sqlstate(code: &str) -> Option<i32> {
+    let bytes = code.as_bytes();
+    if bytes.len() != 5
+        || !bytes
+            .iter()
+            .all(|c| c.is_ascii_digit() || c.is_ascii_uppercase())
+    {
+        return None;
+    }
+
+    Some(bytes.iter().enumerate().fold(0, |acc, (i, c)| {
+        acc | (((*c as i32 - '0' as i32) & 0x3F) << (6 * i))
+    }))
+}
+
+/// `s` without the NUL bytes a C string cannot hold, for text that comes
+/// from the server.
+pub fn cstring(s: &str) -> CString {
+    CString::new(s.replace('\0', "")).unwrap()
+}
+
+#[cfg(feature = "pg13")]
+unsafe fn errstart(level: u32) -> bool {
+    pg_sys::errstart(level as i32, std::ptr::null())
+}
+
+#[cfg(feature = "pg13")]
+unsafe fn errfinish() {
+    pg_sys::errfinish(
+        concat!(file!(), "\0").as_ptr() as *const _,
+        line!() as i32,
+        std::ptr::null(),
+    )
+}
+
+#[cfg(not(feature = "pg13"))]
+unsafe fn errstart(level: u32) -> bool {
+    pg_sys::errstart(
+        level as i32,
+        concat!(file!(), "\0").as_ptr() as *const _,
+        line!() as i32,
+        std::ptr::null(),
+        std::ptr::null(),
+    )
+}
+
+#[cfg(not(feature = "pg13"))]
+unsafe fn errfinish() {
+    pg_sys::errfinish(0);
+}
+
+/// Maps a gRPC status code onto the closest Postgres SQLSTATE.
+pub fn sqlstate(code: Code) -> PgSqlErrorCode {
+    match code {
+        Code::Cancelled | Code::DeadlineExceeded => PgSqlErrorCode::ERRCODE_QUERY_CANCELED,
+        Code::InvalidArgument => PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE,
+        Code::NotFound => PgSqlErrorCode::ERRCODE_UNDEFINED_TABLE,
+        Code::AlreadyExists => PgSqlErrorCode::ERRCODE_UNIQUE_VIOLATION,
+        Code::PermissionDenied => PgSqlErrorCode::ERRCODE_INSUFFICIENT_PRIVILEGE,
+        Code::ResourceExhausted => PgSqlErrorCode::ERRCODE_INSUFFICIENT_RESOURCES,
+        Code::FailedPrecondition => PgSqlErrorCode::ERRCODE_OBJECT_NOT_IN_PREREQUISITE_STATE,
+        Code::Aborted => PgSqlErrorCode::ERRCODE_T_R_SERIALIZATION_FAILURE,
+        Code::OutOfRange => PgSqlErrorCode::ERRCODE_DATA_EXCEPTION,
+        Code::Unimplemented => PgSqlErrorCode::ERRCODE_FEATURE_NOT_SUPPORTED,
+        Code::Unavailable => PgSqlErrorCode::ERRCODE_CONNECTION_FAILURE,
+        Code::DataLoss => PgSqlErrorCode::ERRCODE_DATA_CORRUPTED,
+        Code::Unauthenticated => PgSqlErrorCode::ERRCODE_INVALID_AUTHORIZATION_SPECIFICATION,
+        Code::Ok | Code::Unknown | Code::Internal => PgSqlErrorCode::ERRCODE_INTERNAL_ERROR,
+        _ => PgSqlErrorCode::ERRCODE_FDW_ERROR,
+    }
+}
@@ -0,0 +1,183 @@
+use crate::client::pg::{ExecuteRequest, ExplainResponse, ResultSet};
+use crate::client::Client;
+use crate::error;
+use crate::options::ForeignOptions;
+use crate::shard::Sharding;
+use crate::stats;
+use pgx::*;
+use prost_types::value::Kind;
+use std::ffi::CStr;
+
+/// Shows what a foreign scan asks the server for and, under `ANALYZE`, what
+/// the calls it made added up to. Sorts and limits are never sent to the
+/// server and neither are filters, other than the `=` and `IN` quals on the
+/// shard key of a sharded table that pick the shards called: `Remote
+/// Filter`, `Remote Sort` and `Remote Limit` show which. With `VERBOSE`,
+/// servers implementing `Explain` describe their own plan.
+#[pg_guard]
+pub unsafe extern "C" fn explain_foreign_scan(
+    node: *mut pg_sys::ForeignScanState,
+    es: *mut pg_sys::ExplainState,
+) {
+    let rel = (*node).ss.ss_currentRelation;
+    let relid = (*rel).rd_id;
+    let options = ForeignOptions::load(relid);
+    let namespace = CStr::from_ptr(pg_sys::get_namespace_name(pg_sys::get_rel_namespace(relid)))
+        .to_string_lossy()
+        .into_owned();
+    let name = CStr::from_ptr(pg_sys::get_rel_name(relid))
+        .to_string_lossy()
+        .into_owned();
+
+    property(es, "Endpoints", &options.server_uris().join(", "));
+    let sharding = Sharding::load(&options);
+    if let Some(sharding) = &sharding {
+        let shards = sharding.scan(node);
+        property(
+            es,
+            "Remote Shards",
+            &format!("{} of {}", shards.len(), sharding.all().len()),
+        );
+    }
+    match options.method() {
+        Some(method) => {
+            property(es, "Remote Method", method);
+            if let Some(request) = options.request() {
+                property(es, "Remote Request", request);
+            }
+        }
+        None => property(es, "Remote Table", options.remote_table(&name)),
+    }
+
+    let desc = PgTupleDesc::from_pg_unchecked((*rel).rd_att);
+    let columns: Vec<&str> = desc
+        .iter()
+        .filter(|attr| !attr.is_dropped())
+        .map(|attr| options.remote_column(attr.name()))
+        .collect();
+    property(es, "Remote Columns", &columns.join(", "));
+
+    let quals = sharding.map_or_else(Vec::new, |sharding| sharding.scan_quals(node));
+    property(es, "Remote Filter", &deparse(es, node, quals));
+    property(es, "Remote Sort", "none");
+    property(es, "Remote Limit", "none");
+
+    let table = format!("{}.{}", namespace, name);
+    if (*es).verbose && options.method().is_none() {
+        let request = ExecuteRequest {
+            table: options.remote_table(&name).to_string(),
+            tupdesc: crate::tupdesc_into_map(&desc, &options),
+            partition: Vec::new(),
+        };
+        // The local plan is worth showing even when the server cannot help.
+        match Client::for_table(&options, table).explain(request) {
+            Ok(Some(response)) => remote_plan(es, &response),
+            Ok(None) => {}
+            Err(e) => property(es, "Remote Plan", &format!("unavailable ({})", e.message)),
+        }
+    }
+
+    if (*es).analyze {
+        let totals = stats::scan_totals(node);
+        property(es, "Remote Calls", &totals.calls.to_string());
+        property(es, "Remote Rows", &totals.rows_received.to_string());
+        property(
+            es,
+            "Remote Bytes",
+            &format!(
+                "sent={} received={}",
+                totals.bytes_sent, totals.bytes_received
+            ),
+        );
+        property(
+            es,
+            "Remote Time",
+            &format!("{:.3} ms", totals.time.as_secs_f64() * 1000.0),
+        );
+    }
+}
+
+unsafe fn remote_plan(es: *mut pg_sys::ExplainState, response: &ExplainResponse) {
+    let lines: Vec<&str> = response
+        .plan
+        .lines()
+        .filter(|line| !line.is_empty())
+        .collect();
+    if (*es).format == pg_sys::ExplainFormat_EXPLAIN_FORMAT_TEXT {
+        for line in lines {
+            property(es, "Remote Plan", line);
+        }
+    } else if !lines.is_empty() {
+        let mut list = PgList::<std::os::raw::c_char>::new();
+        for line in lines {
+            let line = error::cstring(line);
+            list.push(pg_sys::pstrdup(line.as_ptr()));
+        }
+        let label = error::cstring("Remote Plan");
+        pg_sys::ExplainPropertyList(label.as_ptr(), list.into_pg(), es);
+    }
+
+    if let Some(properties) = &response.properties {
+        for (key, value) in properties.fields.iter() {
+            property(es, key, &display(value));
+        }
+    }
+}
+
+/// Strings as they are and whole numbers without a fraction, anything else
+/// as JSON.
+fn display(value: &prost_types::Value) -> String {
+    match &value.kind {
+        Some(Kind::StringValue(s)) => s.clone(),
+        Some(Kind::NumberValue(n)) if n.fract() == 0.0 && n.abs() < 1e15 => (*n as i64).to_string(),
+        _ => ResultSet::into_json(value).to_string(),
+    }
+}
+
+/// `quals` of the scan as SQL, `none` if there are none.
+unsafe fn deparse(
+    es: *mut pg_sys::ExplainState,
+    node: *mut pg_sys::ForeignScanState,
+    quals: Vec<*mut pg_sys::Node>,
+) -> String {
+    if quals.is_empty() {
+        return String::from("none");
+    }
+
+    let mut list = PgList::<pg_sys::Node>::new();
+    for qual in quals {
+        list.push(qual);
+    }
+    let expr = pg_sys::make_ands_explicit(list.into_pg()) as *mut pg_sys::Node;
+    let sql = pg_sys::deparse_expression(expr, deparse_context(es, node), (*es).verbose, false);
+
+    CStr::from_ptr(sql).to_string_lossy().into_owned()
+}
+
+#[cfg(feature = "pg13")]
+unsafe fn deparse_context(
+    es: *mut pg_sys::ExplainState,
+    node: *mut pg_sys::ForeignScanState,
+) -> *mut pg_sys::List {
+    pg_sys::set_deparse_context_plan((*es).deparse_cxt, (*node).ss.ps.plan, std::ptr::null_mut())
+}
+
+#[cfg(not(feature = "pg13"))]
+unsafe fn deparse_context(
+    es: *mut pg_sys::ExplainState,
+    node: *mut pg_sys::ForeignScanState,
+) -> *mut pg_sys::List {
+    pg_sys::set_deparse_context_planstate(
+        (*es).deparse_cxt,
+        node as *mut pg_sys::Node,
+        std::ptr::null_mut(),
+    )
+}
+
+/// Labels and values can hold anything the server sent, NUL bytes are left
+/// out.
+unsafe fn property(es: *mut pg_sys::ExplainState, label: &str, value: &str) {
+    let label = error::cstring(label);
+    let value = error::cstring(value);
+    pg_sys::ExplainPropertyText(label.as_ptr(), value.as_ptr(), es);
+}
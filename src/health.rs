@@ -0,0 +1,146 @@
+use crate::connection::{self, block_on};
+use crate::error::RemoteError;
+use crate::metadata;
+use crate::options::ForeignOptions;
+use health::health_check_response::ServingStatus;
+use health::{health_client::HealthClient, HealthCheckRequest};
+use pgx::*;
+use std::time::{Duration, Instant};
+use tonic::transport::Channel;
+
+mod health {
+    tonic::include_proto!("grpc.health.v1");
+}
+
+/// How long a check may take when the server sets neither
+/// `request_timeout` nor `connect_timeout`.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Calls `grpc.health.v1.Health/Check` on every endpoint of `server_name`,
+/// for the whole server or the named `service`. Failures, checks that time
+/// out included, are returned as rows rather than raised, so the function
+/// can back monitoring queries.
+#[pg_extern]
+fn grpc_fdw_health(
+    server_name: &str,
+    service: default!(Option<&str>, NULL),
+) -> impl std::iter::Iterator<
+    Item = (
+        name!(endpoint, String),
+        name!(status, String),
+        name!(latency_ms, Option<f64>),
+        name!(error, Option<String>),
+    ),
+> {
+    let options = ForeignOptions::for_server(server_name);
+    let connection = connection::get(&options);
+    let metadata = metadata::build(&options);
+    let timeout = options
+        .request_timeout()
+        .or_else(|| options.connect_timeout())
+        .unwrap_or(DEFAULT_TIMEOUT);
+    let request = HealthCheckRequest {
+        service: service.unwrap_or_default().to_string(),
+    };
+
+    let rows: Vec<_> = connection
+        .call_each()
+        .into_iter()
+        .map(|(endpoint, call)| {
+            let call = match call {
+                Ok(call) => call,
+                Err(e) => return (endpoint, String::from("UNREACHABLE"), None, Some(e.message)),
+            };
+
+            let started = Instant::now();
+            let mut request = tonic::Request::new(request.clone());
+            *request.metadata_mut() = metadata.clone();
+            let response = block_on(within(
+                timeout,
+                HealthClient::new(call.channel.clone()).check(request),
+            ));
+            let latency = started.elapsed().as_secs_f64() * 1000.0;
+
+            match response {
+                Ok(response) => {
+                    let status = ServingStatus::try_from(response.into_inner().status)
+                        .unwrap_or(ServingStatus::Unknown);
+                    (
+                        endpoint,
+                        status_name(status).to_string(),
+                        Some(latency),
+                        None,
+                    )
+                }
+                Err(status) if status.code() == tonic::Code::NotFound => (
+                    endpoint,
+                    String::from("SERVICE_UNKNOWN"),
+                    Some(latency),
+                    Some(status.message().to_string()),
+                ),
+                Err(status) => {
+                    call.failed(&status);
+                    (
+                        endpoint,
+                        String::from("UNKNOWN"),
+                        Some(latency),
+                        Some(format!("{:?}: {}", status.code(), status.message())),
+                    )
+                }
+            }
+        })
+        .collect();
+
+    rows.into_iter()
+}
+
+/// Checks that an endpoint coming back from ejection serves again before it
+/// is given calls. Servers without the health service pass, only the
+/// passive ejection applies to them.
+pub fn check(channel: Channel, endpoint: &str, timeout: Duration) -> Result<(), RemoteError> {
+    let request = HealthCheckRequest {
+        service: String::new(),
+    };
+    let response = block_on(within(timeout, HealthClient::new(channel).check(request)));
+
+    match response {
+        Ok(response) => match ServingStatus::try_from(response.into_inner().status) {
+            Ok(ServingStatus::Serving) => Ok(()),
+            status => Err(RemoteError::unhealthy(
+                status_name(status.unwrap_or(ServingStatus::Unknown)),
+                endpoint,
+            )),
+        },
+        Err(status) if status.code() == tonic::Code::Unimplemented => Ok(()),
+        Err(status) => Err(RemoteError::from_status(
+            &status,
+            endpoint,
+            "grpc.health.v1.Health/Check",
+        )),
+    }
+}
+
+/// Fails `check` with `DEADLINE_EXCEEDED` once `timeout` passed, `block_on`
+/// would otherwise wait for a hung endpoint forever.
+async fn within<T>(
+    timeout: Duration,
+    check: impl std::future::Future<Output = Result<T, tonic::Status>>,
+) -> Result<T, tonic::Status> {
+    tokio::time::timeout(timeout, check)
+        .await
+        .unwrap_or_else(|_| {
+            Err(tonic::Status::deadline_exceeded(format!(
+                "timed out after {} ms",
+                timeout.as_millis()
+            )))
+        })
+}
+
+fn status_name(status: ServingStatus) -> &'static str {
+    match status {
+        ServingStatus::Unknown => "UNKNOWN",
+        ServingStatus::Serving => "SERVING",
+        ServingStatus::NotServing => "NOT_SERVING",
+        ServingStatus::ServiceUnknown => "SERVICE_UNKNOWN",
+    }
+}
@@ -1,34 +1,62 @@
-use core::convert::TryFrom;
+use options::ForeignOptions;
 use pgx::*;
 use proto_value::ProtoValue;
 use std::collections::HashMap;
 
 mod client;
+mod codec;
+mod connection;
+mod dynamic;
+mod error;
+mod explain;
+mod health;
+mod metadata;
 mod oid;
+mod options;
+mod parallel;
+mod prefetch;
 mod proto_value;
+mod reflection;
+mod retry;
 mod rs;
+mod shard;
+mod stats;
+mod temporal;
+mod tls;
+mod trace;
+mod uds;
 
 pg_module_magic!();
 
-struct FdwWrapper(Vec<client::pg::ResultSet>);
+#[allow(non_snake_case)]
+#[pg_guard]
+pub extern "C" fn _PG_init() {
+    stats::init();
+}
+
+/// The rows of a scan, decoded into the columns of the foreign table.
+struct FdwWrapper(Vec<client::pg::ResultSet>, Vec<Option<rs::Column>>);
 
 impl Iterator for FdwWrapper {
     type Item = Vec<Option<pg_sys::Datum>>;
 
     fn next(&mut self) -> Option<Self::Item> {
         match self.0.pop() {
-            Some(rs) => Some(rs.into_datums()),
+            Some(rs) => Some(rs.into_datums(&self.1)),
             None => None,
         }
     }
 }
 
-fn tupdesc_into_map(desc: &PgTupleDesc) -> HashMap<String, client::pg::Type> {
+fn tupdesc_into_map(
+    desc: &PgTupleDesc,
+    options: &ForeignOptions,
+) -> HashMap<String, client::pg::Type> {
     desc.iter()
         .enumerate()
         .map(|(i, attr)| {
             (
-                attr.name().into(),
+                options.remote_column(attr.name()).into(),
                 client::pg::Type {
                     index: i as i32,
                     oid: client::pg::Oid::from(attr.type_oid()) as i32,
@@ -38,32 +66,85 @@ fn tupdesc_into_map(desc: &PgTupleDesc) -> HashMap<String, client::pg::Type> {
         .collect()
 }
 
+/// The request scanning the whole of foreign table `rel` into rows of
+/// `desc`, along with the qualified name its calls are counted under.
+unsafe fn scan_request(
+    rel: pg_sys::Relation,
+    desc: &PgTupleDesc,
+    options: &ForeignOptions,
+) -> (String, client::pg::ExecuteRequest) {
+    let relid = (*rel).rd_id;
+    let namespace =
+        std::ffi::CStr::from_ptr(pg_sys::get_namespace_name(pg_sys::get_rel_namespace(relid)))
+            .to_string_lossy()
+            .into_owned();
+    let name = std::ffi::CStr::from_ptr(pg_sys::get_rel_name(relid))
+        .to_string_lossy()
+        .into_owned();
+    let request = client::pg::ExecuteRequest {
+        table: options.remote_table(&name).to_string(),
+        tupdesc: tupdesc_into_map(desc, options),
+        partition: Vec::new(),
+    };
+
+    (format!("{}.{}", namespace, name), request)
+}
+
 fn into_values(row: Vec<pgx_fdw::Tuple>) -> Vec<prost_types::Value> {
     row.iter()
         .map(|(_name, datum, typeoid)| match typeoid {
             PgOid::BuiltIn(built_in) => ProtoValue::from_tuple(built_in, datum, typeoid).0,
             PgOid::Custom(_) => ProtoValue::from_tuple(&PgBuiltInOids::ANYOID, datum, typeoid).0,
-            PgOid::InvalidOid => error!("InvalidOid")
+            PgOid::InvalidOid => error!("InvalidOid"),
         })
         .collect()
 }
 
 struct GRPCFdw {
-    client: *mut client::Client,
+    client: client::Client,
     table_name: String,
     namespace: String,
+    /// The local name of the foreign table, for errors.
+    relation: String,
+    options: ForeignOptions,
+    sharding: Option<shard::Sharding>,
 }
 
 impl GRPCFdw {
     pub fn connect(opts: &pgx_fdw::FdwOptions) -> Self {
-        let uri = opts.server_opts.get("server_uri").unwrap();
-        let endpoint = tonic::transport::Endpoint::try_from(uri.clone()).unwrap();
-        let client = client::Client::connect(endpoint).unwrap();
+        let options = ForeignOptions::for_table(&opts.table_namespace, &opts.table_name);
+        let table = format!("{}.{}", opts.table_namespace, opts.table_name);
+        let client = client::Client::for_table(&options, table);
 
         Self {
-            client: Box::into_raw(Box::new(client)) as *mut client::Client,
-            table_name: opts.table_name.clone(),
+            client,
+            table_name: options.remote_table(&opts.table_name).to_string(),
             namespace: opts.table_namespace.clone(),
+            relation: opts.table_name.clone(),
+            sharding: shard::Sharding::load(&options),
+            options,
+        }
+    }
+
+    /// Clients for a write, one per shard it goes to when the table is
+    /// sharded and the balanced one otherwise.
+    fn writers(&self, shards: Option<Vec<usize>>) -> Vec<client::Client> {
+        if let Some(method) = self.options.method() {
+            error::raise(
+                PgSqlErrorCode::ERRCODE_FEATURE_NOT_SUPPORTED,
+                &format!(
+                    "foreign table \"{}\" is read-only, it is mapped to method {}",
+                    self.relation, method
+                ),
+            )
+        }
+
+        match shards {
+            Some(shards) => shards
+                .into_iter()
+                .map(|shard| self.client.on_shard(shard))
+                .collect(),
+            None => vec![self.client.clone()],
         }
     }
 }
@@ -77,26 +158,66 @@ impl pgx_fdw::ForeignData for GRPCFdw {
     }
 
     fn execute(&mut self, desc: &PgTupleDesc) -> Self::RowIterator {
-        let mut client = PgBox::<client::Client>::from_pg(self.client);
-        let request = tonic::Request::new(client::pg::ExecuteRequest {
+        let columns = rs::columns(desc, &self.relation);
+        if let Some(method) = self.options.method() {
+            let names: Vec<String> = desc
+                .iter()
+                .map(|attr| self.options.remote_column(attr.name()).to_string())
+                .collect();
+            let response = self
+                .client
+                .call_method(method, self.options.request(), &names);
+
+            return FdwWrapper(response, columns).into_iter();
+        }
+
+        let request = client::pg::ExecuteRequest {
             table: self.table_name.clone(),
-            tupdesc: tupdesc_into_map(desc),
-        });
+            tupdesc: tupdesc_into_map(desc, &self.options),
+            partition: Vec::new(),
+        };
+
+        if let Some(partitions) = parallel::current() {
+            let mut response = Vec::new();
+            while let Some(partition) = partitions.claim() {
+                response.extend(self.client.execute(client::pg::ExecuteRequest {
+                    partition,
+                    ..request.clone()
+                }));
+            }
+
+            return FdwWrapper(response, columns).into_iter();
+        }
+
+        if let Some(sharding) = &self.sharding {
+            let shards = unsafe { sharding.scan(parallel::current_node()) };
+            let response = self.client.execute_shards(&shards, request);
+
+            return FdwWrapper(response, columns).into_iter();
+        }
 
-        let response = client.execute(request);
+        let response = match prefetch::take(parallel::current_node(), &request) {
+            Some(pending) => self.client.finish_execute(pending),
+            None => self.client.execute(request),
+        };
 
-        FdwWrapper(response).into_iter()
+        FdwWrapper(response, columns).into_iter()
     }
 
     fn insert(&self, desc: &PgTupleDesc, row: Vec<pgx_fdw::Tuple>) -> Option<Vec<pgx_fdw::Tuple>> {
-        let mut client = PgBox::<client::Client>::from_pg(self.client);
-        let request = tonic::Request::new(client::pg::InsertRequest {
+        let shards = self
+            .sharding
+            .as_ref()
+            .map(|sharding| vec![sharding.insert(&row)]);
+        let request = client::pg::InsertRequest {
             table: self.table_name.clone(),
-            tupdesc: tupdesc_into_map(desc),
+            tupdesc: tupdesc_into_map(desc, &self.options),
             tuples: into_values(row),
-        });
+        };
 
-        let _ = client.insert(request);
+        for client in self.writers(shards) {
+            client.insert(request.clone());
+        }
         None
     }
 
@@ -106,15 +227,20 @@ impl pgx_fdw::ForeignData for GRPCFdw {
         row: Vec<pgx_fdw::Tuple>,
         indices: Vec<pgx_fdw::Tuple>,
     ) -> Option<Vec<pgx_fdw::Tuple>> {
-        let mut client = PgBox::<client::Client>::from_pg(self.client);
-        let request = tonic::Request::new(client::pg::UpdateRequest {
+        let shards = self
+            .sharding
+            .as_ref()
+            .map(|sharding| sharding.update(&row, &indices));
+        let request = client::pg::UpdateRequest {
             table: self.table_name.clone(),
-            tupdesc: tupdesc_into_map(desc),
+            tupdesc: tupdesc_into_map(desc, &self.options),
             tuples: into_values(row),
             indices: into_values(indices),
-        });
+        };
 
-        let _ = client.update(request);
+        for client in self.writers(shards) {
+            client.update(request.clone());
+        }
         None
     }
 
@@ -123,14 +249,19 @@ impl pgx_fdw::ForeignData for GRPCFdw {
         desc: &PgTupleDesc,
         tuples: Vec<pgx_fdw::Tuple>,
     ) -> Option<Vec<pgx_fdw::Tuple>> {
-        let mut client = PgBox::<client::Client>::from_pg(self.client);
-        let request = tonic::Request::new(client::pg::DeleteRequest {
+        let shards = self
+            .sharding
+            .as_ref()
+            .map(|sharding| sharding.delete(&tuples));
+        let request = client::pg::DeleteRequest {
             table: self.table_name.clone(),
-            tupdesc: tupdesc_into_map(desc),
+            tupdesc: tupdesc_into_map(desc, &self.options),
             indices: into_values(tuples),
-        });
+        };
 
-        let _ = client.delete(request);
+        for client in self.writers(shards) {
+            client.delete(request.clone());
+        }
         None
     }
 }
@@ -140,9 +271,30 @@ impl pgx_fdw::ForeignData for GRPCFdw {
 /// ```
 #[pg_extern]
 fn grpc_fdw_handler() -> pg_sys::Datum {
-    pgx_fdw::FdwState::<GRPCFdw>::into_datum()
+    let datum = pgx_fdw::FdwState::<GRPCFdw>::into_datum();
+    let mut routine = unsafe { PgBox::<pg_sys::FdwRoutine>::from_pg(datum as *mut _) };
+    routine.ExplainForeignScan = Some(explain::explain_foreign_scan);
+    prefetch::install(&mut routine);
+    parallel::install(&mut routine);
+    stats::install(&mut routine);
+
+    routine.into_pg() as pg_sys::Datum
 }
 
+/// ```sql
+/// CREATE FUNCTION grpc_fdw_validator(text[], oid) RETURNS void LANGUAGE c STRICT AS 'MODULE_PATHNAME', 'grpc_fdw_validator_wrapper';
+/// ```
+#[pg_extern]
+fn grpc_fdw_validator(options: Vec<Option<String>>, catalog: pg_sys::Oid) {
+    options::validate(options, catalog)
+}
+
+extension_sql!(
+    r#"
+CREATE FOREIGN DATA WRAPPER grpc_fdw HANDLER grpc_fdw_handler VALIDATOR grpc_fdw_validator;
+"#
+);
+
 #[cfg(any(test, feature = "pg_test"))]
 mod tests {
     use pgx::*;
@@ -151,16 +303,823 @@ mod tests {
     fn test_hello_grpc_fdw() {
         // assert_eq!("Hello, grpc_fdw", crate::hello_grpc_fdw());
     }
+
+    #[pg_test]
+    fn test_make_sqlstate() {
+        assert_eq!(
+            crate::error::make_sqlstate("23505"),
+            Some(PgSqlErrorCode::ERRCODE_UNIQUE_VIOLATION as i32)
+        );
+        assert_eq!(crate::error::make_sqlstate("2350"), None);
+        assert_eq!(crate::error::make_sqlstate("23x05"), None);
+    }
+
+    #[pg_test]
+    fn test_sqlstate_of_grpc_codes() {
+        use tonic::Code;
+
+        for (code, sqlstate) in [
+            (Code::NotFound, "42P01"),
+            (Code::PermissionDenied, "42501"),
+            (Code::Unavailable, "08006"),
+            (Code::InvalidArgument, "22023"),
+            (Code::DeadlineExceeded, "57014"),
+        ] {
+            assert_eq!(
+                Some(crate::error::sqlstate(code) as i32),
+                crate::error::make_sqlstate(sqlstate),
+                "{:?}",
+                code
+            );
+        }
+    }
+
+    /// Runs `sql` and returns the messages it reported below `ERROR`, with
+    /// their level and detail.
+    fn messages_of(sql: &str) -> Vec<(u32, String, String)> {
+        thread_local! {
+            static MESSAGES: std::cell::RefCell<Vec<(u32, String, String)>> = Default::default();
+        }
+
+        unsafe fn text(s: *const std::os::raw::c_char) -> String {
+            if s.is_null() {
+                return String::new();
+            }
+            std::ffi::CStr::from_ptr(s).to_string_lossy().into_owned()
+        }
+
+        #[pg_guard]
+        unsafe extern "C" fn capture(edata: *mut pg_sys::ErrorData) {
+            if (*edata).message.is_null() {
+                return;
+            }
+            let message = (
+                (*edata).elevel as u32,
+                text((*edata).message),
+                text((*edata).detail),
+            );
+            MESSAGES.with(|m| m.borrow_mut().push(message));
+        }
+
+        // The hook only sees messages that also go to the server log.
+        Spi::run("SET LOCAL log_min_messages = debug5");
+        let previous = unsafe { pg_sys::emit_log_hook };
+        unsafe { pg_sys::emit_log_hook = Some(capture) };
+        Spi::run(sql);
+        unsafe { pg_sys::emit_log_hook = previous };
+
+        MESSAGES.with(|m| m.take())
+    }
+
+    #[pg_test]
+    fn test_write_notices_are_reported_at_their_level() {
+        Spi::run(
+            "CREATE SERVER notice_srv FOREIGN DATA WRAPPER grpc_fdw
+             OPTIONS (server_uri 'http://[::1]:50051');
+             CREATE FOREIGN TABLE notice_tbl (id int, greeting text, source text)
+             SERVER notice_srv",
+        );
+
+        let messages = messages_of("INSERT INTO notice_tbl VALUES (1, 'hello', 'test')");
+        assert!(messages.contains(&(
+            pg_sys::NOTICE,
+            String::from("insert into notice_tbl: 3 values"),
+            String::from("[1, \"hello\", \"test\"]")
+        )));
+    }
+
+    #[pg_test]
+    fn test_connections_are_listed_until_disconnected() {
+        Spi::run(
+            "CREATE SERVER conn_srv FOREIGN DATA WRAPPER grpc_fdw
+             OPTIONS (server_uri 'http://[::1]:50051');
+             CREATE FOREIGN TABLE conn_tbl (id int, greeting text, source text)
+             SERVER conn_srv;
+             SELECT * FROM conn_tbl",
+        );
+        let connections = || {
+            Spi::get_one::<i64>(
+                "SELECT count(*) FROM grpc_fdw_get_connections() WHERE server_name = 'conn_srv'",
+            )
+        };
+
+        assert_eq!(connections(), Some(1));
+        assert_eq!(
+            Spi::get_one::<bool>("SELECT grpc_fdw_disconnect('conn_srv')"),
+            Some(true)
+        );
+        assert_eq!(connections(), Some(0));
+    }
+
+    #[pg_test]
+    fn test_scan_over_unix_socket() {
+        Spi::run(
+            "CREATE SERVER uds_srv FOREIGN DATA WRAPPER grpc_fdw
+             OPTIONS (server_uri 'unix:///tmp/grpc_fdw_test.sock');
+             CREATE FOREIGN TABLE uds_tbl (id int, greeting text, source text)
+             SERVER uds_srv",
+        );
+
+        assert_eq!(
+            Spi::get_one::<String>("SELECT greeting FROM uds_tbl WHERE id = 1"),
+            Some(String::from("Server Says Hello"))
+        );
+    }
+
+    #[pg_test]
+    fn test_stats_count_every_call() {
+        Spi::run(
+            "CREATE SERVER stats_srv FOREIGN DATA WRAPPER grpc_fdw
+             OPTIONS (server_uri 'http://[::1]:50051');
+             CREATE FOREIGN TABLE stats_tbl (id int, greeting text, source text)
+             SERVER stats_srv;
+             SELECT * FROM stats_tbl;
+             SELECT * FROM stats_tbl",
+        );
+        let stats = |columns: &str| {
+            format!(
+                "SELECT {} FROM grpc_fdw_stats() WHERE server_name = 'stats_srv'",
+                columns
+            )
+        };
+
+        assert_eq!(
+            Spi::get_one::<String>(&stats("concat_ws(' ', rpc, status, calls, rows_received)")),
+            Some(String::from("pg.Fdw/Execute OK 2 2"))
+        );
+        assert_eq!(
+            Spi::get_one::<bool>(&stats("p50_ms <= p99_ms AND p99_ms <= max_ms")),
+            Some(true)
+        );
+        assert_eq!(
+            Spi::get_one::<bool>("SELECT grpc_fdw_stats_reset('stats_srv')"),
+            Some(true)
+        );
+        assert_eq!(Spi::get_one::<i64>(&stats("count(*)")), Some(0));
+        assert_eq!(
+            Spi::get_one::<bool>("SELECT grpc_fdw_stats_reset('stats_srv')"),
+            Some(false)
+        );
+    }
+
+    #[pg_test]
+    fn test_prefetched_scan_calls_once() {
+        Spi::run(
+            "CREATE SERVER prefetch_srv FOREIGN DATA WRAPPER grpc_fdw
+             OPTIONS (server_uri 'http://[::1]:50051', async_capable 'true');
+             CREATE FOREIGN TABLE prefetch_tbl (id int, greeting text, source text)
+             SERVER prefetch_srv",
+        );
+
+        assert_eq!(
+            Spi::get_one::<String>("SELECT greeting FROM prefetch_tbl"),
+            Some(String::from("Server Says Hello"))
+        );
+        assert_eq!(
+            Spi::get_one::<i64>(
+                "SELECT calls FROM grpc_fdw_stats()
+                 WHERE server_name = 'prefetch_srv' AND rpc = 'pg.Fdw/Execute'"
+            ),
+            Some(1)
+        );
+    }
+
+    #[pg_test]
+    fn test_parallel_scan_claims_each_partition_once() {
+        Spi::run(
+            "CREATE SERVER parallel_srv FOREIGN DATA WRAPPER grpc_fdw
+             OPTIONS (server_uri 'http://[::1]:50051');
+             CREATE FOREIGN TABLE parallel_tbl (id int, greeting text, source text)
+             SERVER parallel_srv OPTIONS (table_name 'series', parallel_workers '2');
+             SET LOCAL parallel_setup_cost = 0;
+             SET LOCAL parallel_tuple_cost = 0",
+        );
+
+        assert_eq!(
+            Spi::get_one::<String>("EXPLAIN (COSTS OFF) SELECT id FROM parallel_tbl"),
+            Some(String::from("Gather"))
+        );
+        assert_eq!(
+            Spi::get_one::<i64>("SELECT sum(id) FROM parallel_tbl"),
+            Some(10)
+        );
+        // Workers count their calls in shared memory as well.
+        assert_eq!(
+            Spi::get_one::<i64>(
+                "SELECT calls FROM grpc_fdw_stats()
+                 WHERE server_name = 'parallel_srv' AND rpc = 'pg.Fdw/Execute'"
+            ),
+            Some(4)
+        );
+    }
+
+    #[pg_test]
+    fn test_unavailable_calls_are_retried_with_backoff() {
+        Spi::run(
+            "CREATE SERVER retry_srv FOREIGN DATA WRAPPER grpc_fdw
+             OPTIONS (server_uri 'http://[::1]:1', retry_max_attempts '4',
+                      retry_initial_backoff '200', retry_max_backoff '250');
+             CREATE FOREIGN TABLE retry_tbl (id int) SERVER retry_srv",
+        );
+
+        let started = std::time::Instant::now();
+        Spi::run("DO $$ BEGIN PERFORM * FROM retry_tbl; EXCEPTION WHEN OTHERS THEN NULL; END $$");
+        let elapsed = started.elapsed();
+
+        // 200ms, then 250ms twice instead of 400ms and 800ms.
+        assert!(
+            elapsed >= std::time::Duration::from_millis(700)
+                && elapsed < std::time::Duration::from_millis(1400),
+            "{:?}",
+            elapsed
+        );
+        assert_eq!(
+            Spi::get_one::<String>(
+                "SELECT concat_ws(' ', status, calls) FROM grpc_fdw_stats()
+                 WHERE server_name = 'retry_srv'"
+            ),
+            Some(String::from("UNAVAILABLE 4"))
+        );
+    }
+
+    #[pg_test]
+    #[should_panic(expected = "invalid value for option \"retry_codes\"")]
+    fn test_validator_rejects_unknown_retry_code() {
+        Spi::run(
+            "CREATE SERVER codes_srv FOREIGN DATA WRAPPER grpc_fdw
+             OPTIONS (server_uri 'http://[::1]:50051', retry_codes 'unavailable, ABORTED');
+             ALTER SERVER codes_srv OPTIONS (SET retry_codes 'UNAVAILABLE,NOPE')",
+        );
+    }
+
+    /// Creates `edge_tbl` with `columns` over the echo server's `edge_values`
+    /// table: 32768, 1.5, NaN, -Infinity, 2^63 and `AQI=`, the base64 of
+    /// `\x0102`.
+    fn create_edge_table(columns: &str) {
+        Spi::run(&format!(
+            "CREATE SERVER edge_srv FOREIGN DATA WRAPPER grpc_fdw
+             OPTIONS (server_uri 'http://[::1]:50051');
+             CREATE FOREIGN TABLE edge_tbl ({}) SERVER edge_srv
+             OPTIONS (table_name 'edge_values')",
+            columns
+        ));
+    }
+
+    #[pg_test]
+    fn test_decodes_numbers_into_columns() {
+        create_edge_table("a int4, b float8, c float8, d float4, e numeric, f bytea");
+
+        assert_eq!(
+            Spi::get_one::<String>("SELECT concat_ws(' ', a, b, c, d, e, f) FROM edge_tbl"),
+            Some(String::from(
+                "32768 1.5 NaN -Infinity 9223372036854775808 \\x0102"
+            ))
+        );
+    }
+
+    #[pg_test]
+    fn test_decodes_numbers_into_text() {
+        create_edge_table("a text, b text, c text, d text, e text");
+
+        assert_eq!(
+            Spi::get_one::<String>("SELECT concat_ws(' ', a, b, c, d, e) FROM edge_tbl"),
+            Some(String::from("32768 1.5 NaN -Infinity 9223372036854775808"))
+        );
+    }
+
+    #[pg_test]
+    fn test_decodes_numbers_into_json() {
+        create_edge_table("a json, b jsonb, c json, d jsonb");
+
+        assert_eq!(
+            Spi::get_one::<String>("SELECT concat_ws(' ', b, c, d) FROM edge_tbl"),
+            Some(String::from("1.5 \"NaN\" \"-Infinity\""))
+        );
+    }
+
+    #[pg_test]
+    #[should_panic(expected = "cannot convert 32768 to type smallint: out of range")]
+    fn test_rejects_numbers_out_of_range() {
+        create_edge_table("a int2");
+        Spi::run("SELECT * FROM edge_tbl");
+    }
+
+    #[pg_test]
+    #[should_panic(expected = "cannot convert 1.5 to type integer: not an integer")]
+    fn test_rejects_fractions_for_integers() {
+        create_edge_table("a int4, b int4");
+        Spi::run("SELECT * FROM edge_tbl");
+    }
+
+    #[pg_test]
+    #[should_panic(expected = "cannot convert 9223372036854775808 to type bigint: out of range")]
+    fn test_rejects_bigint_beyond_its_range() {
+        create_edge_table("a int8, b float8, c float8, d float8, e int8");
+        Spi::run("SELECT * FROM edge_tbl");
+    }
+
+    #[pg_test]
+    fn test_writes_go_to_the_shard_of_their_key() {
+        Spi::run(
+            "CREATE SERVER shard_srv FOREIGN DATA WRAPPER grpc_fdw
+             OPTIONS (server_uris 'http://[::1]:50051, unix:///tmp/grpc_fdw_test.sock');
+             CREATE FOREIGN TABLE range_tbl (id int, greeting text, source text)
+             SERVER shard_srv OPTIONS (shard_key 'id', shard_map 'range:100');
+             CREATE FOREIGN TABLE hash_tbl (id int, greeting text, source text)
+             SERVER shard_srv OPTIONS (shard_key 'greeting', shard_map 'hash')",
+        );
+        let connected = || {
+            Spi::get_one::<String>(
+                "SELECT string_agg(endpoint, ', ' ORDER BY endpoint) FROM grpc_fdw_get_connections()
+                 WHERE server_name = 'shard_srv' AND connected_at IS NOT NULL",
+            )
+        };
+
+        Spi::run("INSERT INTO range_tbl VALUES (1000, 'hello', 'test')");
+        assert_eq!(
+            connected(),
+            Some(String::from("unix:///tmp/grpc_fdw_test.sock"))
+        );
+
+        Spi::run("SELECT grpc_fdw_disconnect('shard_srv')");
+        Spi::run("INSERT INTO range_tbl VALUES (42, 'hello', 'test')");
+        assert_eq!(connected(), Some(String::from("http://[::1]:50051")));
+
+        // FNV-1a of "a" is even.
+        Spi::run("SELECT grpc_fdw_disconnect('shard_srv')");
+        Spi::run("INSERT INTO hash_tbl VALUES (1, 'a', 'test')");
+        assert_eq!(connected(), Some(String::from("http://[::1]:50051")));
+    }
+
+    /// The plan of the scan `sql` starts with, in `EXPLAIN`'s JSON form.
+    fn explain(verbose: bool, sql: &str) -> serde_json::Value {
+        let explain = format!("EXPLAIN (FORMAT JSON, VERBOSE {}) {}", verbose, sql);
+        let Json(explain) = Spi::get_one::<Json>(&explain).expect("no plan");
+        explain[0]["Plan"].clone()
+    }
+
+    #[pg_test]
+    fn test_explain_shows_what_is_sent_to_the_server() {
+        Spi::run(
+            "CREATE SERVER explain_srv FOREIGN DATA WRAPPER grpc_fdw
+             OPTIONS (server_uris 'http://[::1]:50051, unix:///tmp/grpc_fdw_test.sock');
+             CREATE FOREIGN TABLE explain_tbl (id int, greeting text, source text)
+             SERVER explain_srv OPTIONS (shard_key 'id', shard_map 'range:100')",
+        );
+
+        let plan = explain(
+            false,
+            "SELECT * FROM explain_tbl WHERE id = 42 AND greeting <> ''",
+        );
+        assert_eq!(plan["Remote Shards"], "1 of 2");
+        assert_eq!(plan["Remote Filter"], "(id = 42)");
+        assert_eq!(plan["Remote Sort"], "none");
+        assert_eq!(plan["Remote Limit"], "none");
+
+        let plan = explain(false, "SELECT * FROM explain_tbl WHERE greeting <> ''");
+        assert_eq!(plan["Remote Shards"], "2 of 2");
+        assert_eq!(plan["Remote Filter"], "none");
+    }
+
+    #[pg_test]
+    fn test_explain_verbose_shows_the_remote_plan() {
+        Spi::run(
+            "CREATE SERVER remote_plan_srv FOREIGN DATA WRAPPER grpc_fdw
+             OPTIONS (server_uri 'http://[::1]:50051');
+             CREATE FOREIGN TABLE remote_plan_tbl (id int, greeting text, source text)
+             SERVER remote_plan_srv OPTIONS (table_name 'series');
+             CREATE FOREIGN TABLE nul_plan_tbl (id int, greeting text, source text)
+             SERVER remote_plan_srv OPTIONS (table_name 'nul\\0bytes')",
+        );
+
+        let plan = explain(true, "SELECT * FROM remote_plan_tbl");
+        assert_eq!(
+            plan["Remote Plan"],
+            serde_json::json!(["Echo rows of series"])
+        );
+        assert_eq!(plan["Echoed Rows"], "4");
+
+        // The echo server turns the `\0` into a NUL byte, which is left out.
+        let plan = explain(true, "SELECT * FROM nul_plan_tbl");
+        assert_eq!(
+            plan["Remote Plan"],
+            serde_json::json!(["Echo rows of nulbytes"])
+        );
+    }
+
+    #[pg_test]
+    #[should_panic(
+        expected = "invalid shard_map: 2 range bounds need 3 endpoints, the server has 2"
+    )]
+    fn test_rejects_shard_map_not_covering_the_endpoints() {
+        Spi::run(
+            "CREATE SERVER short_map_srv FOREIGN DATA WRAPPER grpc_fdw
+             OPTIONS (server_uris 'http://[::1]:50051, unix:///tmp/grpc_fdw_test.sock');
+             CREATE FOREIGN TABLE short_map_tbl (id int, greeting text, source text)
+             SERVER short_map_srv OPTIONS (shard_key 'id', shard_map 'range:100,200');
+             SELECT * FROM short_map_tbl",
+        );
+    }
+
+    #[pg_test]
+    fn test_calls_continue_the_trace_of_the_session() {
+        const PARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        const INVALID: &str = "00-00000000000000000000000000000000-00f067aa0ba902b7-01";
+        Spi::run(
+            "CREATE SERVER trace_srv FOREIGN DATA WRAPPER grpc_fdw
+             OPTIONS (server_uri 'http://[::1]:50051');
+             CREATE FOREIGN TABLE trace_tbl (id int, greeting text, source text)
+             SERVER trace_srv",
+        );
+        // The traceparents used and the warnings reported by a scan.
+        let scan = |traceparent: &str| {
+            let messages = messages_of(&format!(
+                "SET LOCAL grpc_fdw.traceparent = '{}'; SELECT * FROM trace_tbl",
+                traceparent
+            ));
+            let used: Vec<String> = messages
+                .iter()
+                .filter(|(level, ..)| *level == pg_sys::DEBUG1)
+                .filter_map(|(_, message, _)| {
+                    message.strip_prefix("grpc_fdw: calls to server \"trace_srv\" use traceparent ")
+                })
+                .map(String::from)
+                .collect();
+            let warnings: Vec<String> = messages
+                .into_iter()
+                .filter(|(level, ..)| *level == pg_sys::WARNING)
+                .map(|(_, message, _)| message)
+                .collect();
+
+            (used, warnings)
+        };
+
+        let (used, warnings) = scan(PARENT);
+        assert!(!used.is_empty() && warnings.is_empty());
+        for child in used {
+            assert!(child.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+            assert!(child.ends_with("-01"));
+            assert_ne!(child, PARENT);
+        }
+
+        let (used, warnings) = scan(INVALID);
+        assert!(!used.is_empty() && !warnings.is_empty());
+        for root in used {
+            assert!(!root.starts_with("00-00000000000000000000000000000000-"));
+            assert!(root.ends_with("-01"));
+        }
+        for warning in warnings {
+            assert_eq!(
+                warning,
+                format!(
+                    "grpc_fdw.traceparent is ignored: \"{}\" is not a valid traceparent",
+                    INVALID
+                )
+            );
+        }
+    }
+
+    /// Inserts `values` into `table` and returns them the way the echo server
+    /// received them, as JSON.
+    fn sent(table: &str, values: &str) -> Option<String> {
+        let insert = format!("INSERT INTO {} VALUES ({})", table, values);
+        messages_of(&insert)
+            .into_iter()
+            .find(|(_, message, _)| message.starts_with(&format!("insert into {}:", table)))
+            .map(|(_, _, detail)| detail)
+    }
+
+    #[pg_test]
+    fn test_writes_send_numbers_exactly() {
+        Spi::run(
+            "CREATE SERVER numbers_srv FOREIGN DATA WRAPPER grpc_fdw
+             OPTIONS (server_uri 'http://[::1]:50051');
+             CREATE FOREIGN TABLE numbers_tbl
+             (big int8, exact int8, f4 float4, f8 float8, inf float8, frac float8)
+             SERVER numbers_srv",
+        );
+
+        // Beyond 2^53 a bigint would lose digits as a double, NaN and the
+        // infinities have no JSON number form.
+        assert_eq!(
+            sent(
+                "numbers_tbl",
+                "9007199254740993, -9007199254740992, 'NaN', '-Infinity', 'Infinity', 1.5"
+            ),
+            Some(String::from(
+                "[\"9007199254740993\", -9007199254740992, \"NaN\", \"-Infinity\", \
+                 \"Infinity\", 1.5]"
+            ))
+        );
+    }
+
+    #[pg_test]
+    fn test_writes_send_other_types_as_text() {
+        Spi::run(
+            "CREATE TYPE encoding_mood AS ENUM ('happy', 'sad');
+             CREATE SERVER encoding_srv FOREIGN DATA WRAPPER grpc_fdw
+             OPTIONS (server_uri 'http://[::1]:50051');
+             CREATE FOREIGN TABLE encoding_tbl
+             (b bytea, u uuid, i interval, ttz timetz, n numeric, ip inet, m encoding_mood,
+              j jsonb, nothing text)
+             SERVER encoding_srv",
+        );
+
+        // bytea goes as base64, numeric keeps its scale and types without a
+        // mapping of their own, built in or not, go as they print.
+        assert_eq!(
+            sent(
+                "encoding_tbl",
+                "'\\x0102ff', 'a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a11', '1 day 02:00:00',
+                 '12:30:00+05:30', 123.4500, '10.0.0.1/8', 'happy', '{\"a\": [1, null, true]}',
+                 NULL"
+            ),
+            Some(String::from(
+                "[\"AQL/\", \"a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a11\", \"P1DT2H\", \
+                 \"12:30:00+05:30\", \"123.4500\", \"10.0.0.1/8\", \"happy\", \
+                 {\"a\": [1, null, true]}, null]"
+            ))
+        );
+    }
+
+    #[pg_test]
+    fn test_writes_send_dates_and_times_in_iso_8601() {
+        Spi::run(
+            "CREATE SERVER temporal_srv FOREIGN DATA WRAPPER grpc_fdw
+             OPTIONS (server_uri 'http://[::1]:50051');
+             CREATE FOREIGN TABLE temporal_tbl
+             (d date, t time, ttz timetz, ts timestamp, tstz timestamptz, i interval)
+             SERVER temporal_srv;
+             SET LOCAL TimeZone = 'UTC'",
+        );
+
+        assert_eq!(
+            sent(
+                "temporal_tbl",
+                "'2021-03-04', '05:00:00.00012', '05:00:00+02', '2021-03-04 05:06:07.5',
+                 '2021-03-04 05:06:07.5+00', '1 year 2 mons 3 days 04:05:06.5'"
+            ),
+            Some(String::from(
+                "[\"2021-03-04\", \"05:00:00.00012\", \"05:00:00+02:00\", \
+                 \"2021-03-04T05:06:07.5\", \"2021-03-04T05:06:07.5Z\", \"P1Y2M3DT4H5M6.5S\"]"
+            ))
+        );
+        assert_eq!(
+            sent(
+                "temporal_tbl",
+                "'0001-01-01 BC', '00:00:00', '00:00:00-05:30', '-infinity', 'infinity',
+                 '-1 mons -90 seconds'"
+            ),
+            Some(String::from(
+                "[\"0000-01-01\", \"00:00:00\", \"00:00:00-05:30\", \"-infinity\", \
+                 \"infinity\", \"P-1MT-1M-30S\"]"
+            ))
+        );
+    }
+
+    /// Creates `users_srv` with the echo server's checked in descriptor set.
+    fn create_users_server() {
+        Spi::run(&format!(
+            "CREATE SERVER users_srv FOREIGN DATA WRAPPER grpc_fdw
+             OPTIONS (server_uri 'http://[::1]:50051', descriptor_set '{}/fdw_server/proto/users.pb')",
+            env!("CARGO_MANIFEST_DIR")
+        ));
+    }
+
+    #[pg_test]
+    fn test_method_table_from_descriptor_set() {
+        create_users_server();
+        Spi::run(
+            "CREATE FOREIGN TABLE users_tbl (id bigint, \"displayName\" text, avatar bytea)
+             SERVER users_srv OPTIONS (method 'acme.users.v1.UserService/ListUsers');
+             CREATE FOREIGN TABLE first_user_tbl (id bigint) SERVER users_srv
+             OPTIONS (method 'acme.users.v1.UserService/ListUsers', request '{\"pageSize\": 1}')",
+        );
+
+        assert_eq!(
+            Spi::get_one::<i64>("SELECT count(*) FROM users_tbl"),
+            Some(2)
+        );
+        assert_eq!(
+            Spi::get_one::<String>("SELECT \"displayName\" FROM users_tbl WHERE id = 2"),
+            Some(String::from("Grace"))
+        );
+        assert_eq!(
+            Spi::get_one::<String>("SELECT avatar::text FROM users_tbl WHERE id = 1"),
+            Some(String::from("\\x01ff"))
+        );
+        assert_eq!(
+            Spi::get_one::<i64>("SELECT count(*) FROM first_user_tbl"),
+            Some(1)
+        );
+    }
+
+    #[pg_test]
+    #[should_panic(expected = "method \"acme.users.v1.UserService/RemoveUsers\" not found")]
+    fn test_method_missing_from_descriptor_set() {
+        create_users_server();
+        Spi::run(
+            "CREATE FOREIGN TABLE no_method_tbl (id bigint) SERVER users_srv
+             OPTIONS (method 'acme.users.v1.UserService/RemoveUsers');
+             SELECT * FROM no_method_tbl",
+        );
+    }
+
+    #[pg_test]
+    fn test_health_of_echo_server() {
+        Spi::run(
+            "CREATE SERVER health_srv FOREIGN DATA WRAPPER grpc_fdw
+             OPTIONS (server_uri 'http://[::1]:50051')",
+        );
+        let status = |service: &str| {
+            Spi::get_one::<String>(&format!(
+                "SELECT status FROM grpc_fdw_health('health_srv', {})",
+                service
+            ))
+        };
+
+        assert_eq!(status("NULL"), Some(String::from("SERVING")));
+        assert_eq!(status("'pg.Fdw'"), Some(String::from("SERVING")));
+        assert_eq!(status("'acme.Nope'"), Some(String::from("SERVICE_UNKNOWN")));
+    }
+
+    #[pg_test]
+    fn test_health_check_times_out() {
+        // Accepts connections into its backlog and never answers.
+        let listener = std::net::TcpListener::bind("[::1]:0").unwrap();
+        Spi::run(&format!(
+            "CREATE SERVER hung_srv FOREIGN DATA WRAPPER grpc_fdw
+             OPTIONS (server_uri 'http://{}', connect_timeout '1', request_timeout '200')",
+            listener.local_addr().unwrap()
+        ));
+
+        assert_eq!(
+            Spi::get_one::<String>(
+                "SELECT status FROM grpc_fdw_health('hung_srv') WHERE error LIKE '%timed out%'"
+            ),
+            Some(String::from("UNKNOWN"))
+        );
+    }
+
+    #[pg_test]
+    fn test_validator_accepts_known_options() {
+        Spi::run(
+            "CREATE SERVER valid_srv FOREIGN DATA WRAPPER grpc_fdw
+             OPTIONS (server_uri 'http://[::1]:50051', connect_timeout '5')",
+        );
+    }
+
+    #[pg_test]
+    #[should_panic(expected = "invalid option \"sever_uri\"")]
+    fn test_validator_rejects_unknown_option() {
+        Spi::run(
+            "CREATE SERVER typo_srv FOREIGN DATA WRAPPER grpc_fdw
+             OPTIONS (sever_uri 'http://[::1]:50051')",
+        );
+    }
+
+    #[pg_test]
+    #[should_panic(expected = "invalid value for option \"connect_timeout\"")]
+    fn test_validator_rejects_out_of_range_value() {
+        Spi::run(
+            "CREATE SERVER range_srv FOREIGN DATA WRAPPER grpc_fdw
+             OPTIONS (server_uri 'http://[::1]:50051', connect_timeout '-1')",
+        );
+    }
+
+    #[pg_test]
+    #[should_panic(expected = "invalid value for option \"method\"")]
+    fn test_validator_rejects_malformed_method() {
+        Spi::run(
+            "CREATE SERVER method_srv FOREIGN DATA WRAPPER grpc_fdw
+             OPTIONS (server_uri 'http://[::1]:50051');
+             CREATE FOREIGN TABLE method_tbl (id int) SERVER method_srv
+             OPTIONS (method 'acme.users.v1.UserService')",
+        );
+    }
+
+    #[pg_test]
+    #[should_panic(expected = "foreign table \"method_rw_tbl\" is read-only")]
+    fn test_method_tables_reject_writes() {
+        Spi::run(
+            "CREATE SERVER method_rw_srv FOREIGN DATA WRAPPER grpc_fdw
+             OPTIONS (server_uri 'http://[::1]:50051');
+             CREATE FOREIGN TABLE method_rw_tbl (id int) SERVER method_rw_srv
+             OPTIONS (method 'acme.users.v1.UserService/ListUsers');
+             INSERT INTO method_rw_tbl VALUES (1)",
+        );
+    }
+
+    #[pg_test]
+    #[should_panic(expected = "invalid value for option \"shard_map\"")]
+    fn test_validator_rejects_unordered_shard_map() {
+        Spi::run(
+            "CREATE SERVER shard_srv FOREIGN DATA WRAPPER grpc_fdw
+             OPTIONS (server_uris 'http://[::1]:50051,http://[::1]:50052,http://[::1]:50053');
+             CREATE FOREIGN TABLE shard_tbl (id int) SERVER shard_srv
+             OPTIONS (shard_key 'id', shard_map 'range:200,100')",
+        );
+    }
+
+    #[pg_test]
+    #[should_panic(expected = "invalid shard_key: the table has no column \"uid\"")]
+    fn test_rejects_unknown_shard_key() {
+        Spi::run(
+            "CREATE SERVER shard_key_srv FOREIGN DATA WRAPPER grpc_fdw
+             OPTIONS (server_uris 'http://[::1]:50051,http://[::1]:50052');
+             CREATE FOREIGN TABLE shard_key_tbl (id int) SERVER shard_key_srv
+             OPTIONS (shard_key 'uid');
+             INSERT INTO shard_key_tbl VALUES (1)",
+        );
+    }
+
+    #[pg_test]
+    fn test_validator_accepts_headers() {
+        Spi::run(
+            "CREATE SERVER header_srv FOREIGN DATA WRAPPER grpc_fdw
+             OPTIONS (server_uri 'http://[::1]:50051', \"header.x-tenant\" 'acme');
+             CREATE USER MAPPING FOR PUBLIC SERVER header_srv
+             OPTIONS (\"header.authorization\" 'Bearer secret')",
+        );
+    }
+
+    #[pg_test]
+    #[should_panic(expected = "invalid metadata option \"header.grpc-timeout\"")]
+    fn test_validator_rejects_reserved_header() {
+        Spi::run(
+            "CREATE SERVER reserved_srv FOREIGN DATA WRAPPER grpc_fdw
+             OPTIONS (server_uri 'http://[::1]:50051', \"header.grpc-timeout\" '1S')",
+        );
+    }
+
+    #[pg_test]
+    #[should_panic(expected = "invalid metadata option \"header.x-pg-user\"")]
+    fn test_validator_rejects_session_header() {
+        Spi::run(
+            "CREATE SERVER session_srv FOREIGN DATA WRAPPER grpc_fdw
+             OPTIONS (server_uri 'http://[::1]:50051');
+             CREATE FOREIGN TABLE session_tbl (id int) SERVER session_srv
+             OPTIONS (\"header.x-pg-user\" 'postgres')",
+        );
+    }
+
+    #[pg_test]
+    #[should_panic(expected = "server_uri and server_uris cannot be used together")]
+    fn test_validator_rejects_server_uri_with_server_uris() {
+        Spi::run(
+            "CREATE SERVER both_srv FOREIGN DATA WRAPPER grpc_fdw
+             OPTIONS (server_uri 'http://[::1]:50051',
+                      server_uris 'http://[::1]:50051, http://[::1]:50052')",
+        );
+    }
 }
 
 #[cfg(test)]
 pub mod pg_test {
+    use std::path::Path;
+    use std::process::{Command, Stdio};
+    use std::sync::Once;
+    use std::time::{Duration, Instant};
+
+    /// Where the tests reach the `fdw-server` echo server, over TCP and over
+    /// a Unix domain socket.
+    const ECHO_ADDR: &str = "[::1]:50051";
+    const ECHO_SOCKET: &str = "/tmp/grpc_fdw_test.sock";
+
+    static ECHO_SERVERS: Once = Once::new();
+
     pub fn setup(_options: Vec<&str>) {
-        // perform one-off initialization when the pg_test framework starts
+        ECHO_SERVERS.call_once(|| {
+            start_echo_server(ECHO_ADDR);
+            start_echo_server(&format!("unix://{}", ECHO_SOCKET));
+
+            let started = Instant::now();
+            while std::net::TcpStream::connect(ECHO_ADDR).is_err()
+                || std::os::unix::net::UnixStream::connect(ECHO_SOCKET).is_err()
+            {
+                assert!(
+                    started.elapsed() < Duration::from_secs(300),
+                    "fdw-server did not start"
+                );
+                std::thread::sleep(Duration::from_millis(200));
+            }
+        });
+    }
+
+    /// Builds and runs the bundled echo server, which outlives the tests.
+    fn start_echo_server(listen: &str) {
+        let manifest = Path::new(env!("CARGO_MANIFEST_DIR")).join("fdw_server/Cargo.toml");
+        Command::new(env!("CARGO"))
+            .args(["run", "--quiet", "--bin", "fdw-server", "--manifest-path"])
+            .arg(manifest)
+            .args(["--", listen])
+            .stdout(Stdio::null())
+            .spawn()
+            .expect("could not start fdw-server");
     }
 
     pub fn postgresql_conf_options() -> Vec<&'static str> {
-        // return any postgresql.conf settings that are required for your tests
-        vec![]
+        // Call statistics live in shared memory, as in production.
+        vec!["shared_preload_libraries = 'grpc_fdw'"]
     }
 }
@@ -0,0 +1,137 @@
+use crate::options::ForeignOptions;
+use crate::trace::TraceContext;
+use pgx::*;
+use std::ffi::{CStr, CString};
+use tonic::metadata::{AsciiMetadataKey, AsciiMetadataValue, MetadataMap};
+
+/// Options named `header.<key>` become request metadata.
+pub const HEADER_PREFIX: &str = "header.";
+
+/// Checks the key and value of a `header.<key>` option.
+pub fn check_header(key: &str, value: &str) -> Result<(), String> {
+    parse_header(key, value).map(|_| ())
+}
+
+fn parse_header(key: &str, value: &str) -> Result<(AsciiMetadataKey, AsciiMetadataValue), String> {
+    // `x-pg-*` carry the calling session, which table owners must not forge.
+    let lower = key.to_ascii_lowercase();
+    if lower.starts_with("grpc-") || lower.starts_with("x-pg-") {
+        return Err(format!("\"{}\" is reserved", key));
+    }
+    if lower.ends_with("-bin") {
+        return Err(String::from("binary metadata is not supported"));
+    }
+
+    let key = AsciiMetadataKey::from_bytes(key.as_bytes())
+        .map_err(|_| format!("\"{}\" is not a valid metadata key", key))?;
+    let value = ascii_value(value)
+        .ok_or_else(|| String::from("metadata values must be printable ASCII"))?;
+
+    Ok((key, value))
+}
+
+fn ascii_value(value: &str) -> Option<AsciiMetadataValue> {
+    if value.bytes().all(|b| (b' '..=b'~').contains(&b)) {
+        AsciiMetadataValue::try_from(value).ok()
+    } else {
+        None
+    }
+}
+
+/// Metadata sent with every call: the session of the calling backend,
+/// unless `session_metadata` is off, the trace context of a new span, and
+/// the `header.*` options of the server, table and user mapping, each
+/// overriding the one before.
+pub fn build(options: &ForeignOptions) -> MetadataMap {
+    let mut metadata = MetadataMap::new();
+
+    if options.session_metadata() {
+        for (key, value) in session() {
+            if let Some(value) = ascii_value(&value) {
+                metadata.insert(key, value);
+            }
+        }
+    }
+
+    let trace = trace();
+    let traceparent = trace.traceparent();
+    debug1!(
+        "grpc_fdw: calls to server \"{}\" use traceparent {}",
+        options.server_name,
+        traceparent
+    );
+    if let Some(value) = ascii_value(&traceparent) {
+        metadata.insert("traceparent", value);
+    }
+    if let Some(value) = trace.state.as_deref().and_then(ascii_value) {
+        metadata.insert("tracestate", value);
+    }
+
+    for (key, value) in options.headers() {
+        if let Ok((key, value)) = parse_header(key, value) {
+            metadata.insert(key, value);
+        }
+    }
+
+    metadata
+}
+
+fn session() -> Vec<(&'static str, String)> {
+    unsafe {
+        let user = CStr::from_ptr(pg_sys::GetUserNameFromId(pg_sys::GetUserId(), false))
+            .to_string_lossy()
+            .into_owned();
+        let database = CStr::from_ptr(pg_sys::get_database_name(pg_sys::MyDatabaseId))
+            .to_string_lossy()
+            .into_owned();
+
+        let mut session = vec![
+            ("x-pg-user", user),
+            ("x-pg-database", database),
+            ("x-pg-backend-pid", pg_sys::MyProcPid.to_string()),
+        ];
+        if let Some(application_name) = setting("application_name") {
+            session.push(("x-pg-application-name", application_name));
+        }
+
+        session
+    }
+}
+
+/// Starts the span of one scan or modification, in the trace given by the
+/// `grpc_fdw.traceparent` setting if there is one.
+fn trace() -> TraceContext {
+    let parent = setting("grpc_fdw.traceparent").and_then(|traceparent| {
+        let state = setting("grpc_fdw.tracestate");
+        match TraceContext::parse(&traceparent, state.as_deref()) {
+            Ok(parent) => Some(parent),
+            Err(e) => {
+                warning!("grpc_fdw.traceparent is ignored: {}", e);
+                None
+            }
+        }
+    });
+
+    let mut random = [0u8; 24];
+    unsafe {
+        if !pg_sys::pg_strong_random(random.as_mut_ptr() as *mut std::os::raw::c_void, 24) {
+            error!("could not generate random trace ids");
+        }
+    }
+
+    TraceContext::start(parent.as_ref(), random)
+}
+
+/// The value of a setting, `None` if it is unset, empty or unknown. Custom
+/// settings such as `grpc_fdw.traceparent` work without being declared.
+fn setting(name: &str) -> Option<String> {
+    let name = CString::new(name).unwrap();
+    unsafe {
+        let value = pg_sys::GetConfigOption(name.as_ptr(), true, false);
+        if value.is_null() || *value == 0 {
+            return None;
+        }
+
+        Some(CStr::from_ptr(value).to_string_lossy().into_owned())
+    }
+}
@@ -0,0 +1,808 @@
+use crate::error;
+use pgx::*;
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Catalog {
+    Wrapper,
+    Server,
+    UserMapping,
+    Table,
+    Column,
+}
+
+impl Catalog {
+    fn from_oid(oid: pg_sys::Oid) -> Option<Self> {
+        match oid {
+            pg_sys::ForeignDataWrapperRelationId => Some(Catalog::Wrapper),
+            pg_sys::ForeignServerRelationId => Some(Catalog::Server),
+            pg_sys::UserMappingRelationId => Some(Catalog::UserMapping),
+            pg_sys::ForeignTableRelationId => Some(Catalog::Table),
+            pg_sys::AttributeRelationId => Some(Catalog::Column),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    Uri,
+    UriList,
+    Bool,
+    Int { min: i64, max: i64 },
+    Name,
+    Path,
+    CodeList,
+    Method,
+    Json,
+    ShardMap,
+    Enum(&'static [&'static str]),
+}
+
+struct OptionDef {
+    name: &'static str,
+    catalogs: &'static [Catalog],
+    kind: Kind,
+}
+
+/// Every option grpc_fdw understands, and where it may be set.
+const OPTIONS: &[OptionDef] = &[
+    OptionDef {
+        name: "server_uri",
+        catalogs: &[Catalog::Server],
+        kind: Kind::Uri,
+    },
+    OptionDef {
+        name: "server_uris",
+        catalogs: &[Catalog::Server],
+        kind: Kind::UriList,
+    },
+    OptionDef {
+        name: "load_balancing",
+        catalogs: &[Catalog::Server],
+        kind: Kind::Enum(crate::connection::POLICIES),
+    },
+    OptionDef {
+        name: "ejection_time",
+        catalogs: &[Catalog::Server],
+        kind: Kind::Int { min: 1, max: 3600 },
+    },
+    OptionDef {
+        name: "tls_ca_file",
+        catalogs: &[Catalog::Server],
+        kind: Kind::Path,
+    },
+    OptionDef {
+        name: "tls_cert_file",
+        catalogs: &[Catalog::Server],
+        kind: Kind::Path,
+    },
+    OptionDef {
+        name: "tls_key_file",
+        catalogs: &[Catalog::Server],
+        kind: Kind::Path,
+    },
+    OptionDef {
+        name: "tls_domain",
+        catalogs: &[Catalog::Server],
+        kind: Kind::Name,
+    },
+    OptionDef {
+        name: "tls_verify",
+        catalogs: &[Catalog::Server],
+        kind: Kind::Enum(crate::tls::VERIFY_MODES),
+    },
+    OptionDef {
+        name: "compression",
+        catalogs: &[Catalog::Server],
+        kind: Kind::Enum(&["none", "gzip", "zstd"]),
+    },
+    OptionDef {
+        name: "max_decoding_message_size",
+        catalogs: &[Catalog::Server],
+        kind: Kind::Int {
+            min: 1,
+            max: i32::MAX as i64,
+        },
+    },
+    OptionDef {
+        name: "max_encoding_message_size",
+        catalogs: &[Catalog::Server],
+        kind: Kind::Int {
+            min: 1,
+            max: i32::MAX as i64,
+        },
+    },
+    OptionDef {
+        name: "descriptor_set",
+        catalogs: &[Catalog::Server],
+        kind: Kind::Path,
+    },
+    OptionDef {
+        name: "connect_timeout",
+        catalogs: &[Catalog::Server],
+        kind: Kind::Int { min: 0, max: 3600 },
+    },
+    OptionDef {
+        name: "keepalive_interval",
+        catalogs: &[Catalog::Server],
+        kind: Kind::Int {
+            min: 0,
+            max: 86_400,
+        },
+    },
+    OptionDef {
+        name: "keepalive_timeout",
+        catalogs: &[Catalog::Server],
+        kind: Kind::Int { min: 1, max: 3600 },
+    },
+    OptionDef {
+        name: "initial_stream_window_size",
+        catalogs: &[Catalog::Server],
+        kind: Kind::Int {
+            min: 1,
+            max: i32::MAX as i64,
+        },
+    },
+    OptionDef {
+        name: "initial_connection_window_size",
+        catalogs: &[Catalog::Server],
+        kind: Kind::Int {
+            min: 1,
+            max: i32::MAX as i64,
+        },
+    },
+    OptionDef {
+        name: "tcp_nodelay",
+        catalogs: &[Catalog::Server],
+        kind: Kind::Bool,
+    },
+    OptionDef {
+        name: "request_timeout",
+        catalogs: &[Catalog::Server, Catalog::Table],
+        kind: Kind::Int {
+            min: 0,
+            max: i32::MAX as i64,
+        },
+    },
+    OptionDef {
+        name: "retry_max_attempts",
+        catalogs: &[Catalog::Server, Catalog::Table],
+        kind: Kind::Int { min: 1, max: 10 },
+    },
+    OptionDef {
+        name: "retry_initial_backoff",
+        catalogs: &[Catalog::Server, Catalog::Table],
+        kind: Kind::Int {
+            min: 0,
+            max: 60_000,
+        },
+    },
+    OptionDef {
+        name: "retry_max_backoff",
+        catalogs: &[Catalog::Server, Catalog::Table],
+        kind: Kind::Int {
+            min: 0,
+            max: 3_600_000,
+        },
+    },
+    OptionDef {
+        name: "retry_codes",
+        catalogs: &[Catalog::Server, Catalog::Table],
+        kind: Kind::CodeList,
+    },
+    OptionDef {
+        name: "session_metadata",
+        catalogs: &[Catalog::Server],
+        kind: Kind::Bool,
+    },
+    OptionDef {
+        name: "async_capable",
+        catalogs: &[Catalog::Server, Catalog::Table],
+        kind: Kind::Bool,
+    },
+    OptionDef {
+        name: "parallel_workers",
+        catalogs: &[Catalog::Server, Catalog::Table],
+        kind: Kind::Int { min: 0, max: 1024 },
+    },
+    OptionDef {
+        name: "table_name",
+        catalogs: &[Catalog::Table],
+        kind: Kind::Name,
+    },
+    OptionDef {
+        name: "method",
+        catalogs: &[Catalog::Table],
+        kind: Kind::Method,
+    },
+    OptionDef {
+        name: "request",
+        catalogs: &[Catalog::Table],
+        kind: Kind::Json,
+    },
+    OptionDef {
+        name: "shard_key",
+        catalogs: &[Catalog::Table],
+        kind: Kind::Name,
+    },
+    OptionDef {
+        name: "shard_map",
+        catalogs: &[Catalog::Table],
+        kind: Kind::ShardMap,
+    },
+    OptionDef {
+        name: "column_name",
+        catalogs: &[Catalog::Column],
+        kind: Kind::Name,
+    },
+];
+
+/// Where `header.<key>` options may be set.
+const HEADER_CATALOGS: &[Catalog] = &[Catalog::Server, Catalog::Table, Catalog::UserMapping];
+
+/// Checks the options given to `CREATE`/`ALTER` of a grpc_fdw object.
+pub fn validate(options: Vec<Option<String>>, catalog: pg_sys::Oid) {
+    let catalog = match Catalog::from_oid(catalog) {
+        Some(catalog) => catalog,
+        None => error::raise(
+            PgSqlErrorCode::ERRCODE_FDW_ERROR,
+            &format!("grpc_fdw options are not supported for catalog {}", catalog),
+        ),
+    };
+
+    let options: Vec<(String, String)> = options
+        .into_iter()
+        .flatten()
+        .map(|option| match option.find('=') {
+            Some(i) => (option[..i].to_string(), option[i + 1..].to_string()),
+            None => (option, String::new()),
+        })
+        .collect();
+
+    for (name, value) in options.iter() {
+        let (name, value) = (name.as_str(), value.as_str());
+
+        if let Some(key) = name.strip_prefix(crate::metadata::HEADER_PREFIX) {
+            if !HEADER_CATALOGS.contains(&catalog) {
+                error::raise_with_hint(
+                    PgSqlErrorCode::ERRCODE_FDW_INVALID_OPTION_NAME,
+                    &format!("invalid option \"{}\"", name),
+                    &valid_options_hint(catalog),
+                );
+            }
+            if let Err(message) = crate::metadata::check_header(key, value) {
+                error::raise(
+                    PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE,
+                    &format!("invalid metadata option \"{}\": {}", name, message),
+                );
+            }
+            continue;
+        }
+
+        let def = match OPTIONS
+            .iter()
+            .find(|def| def.name == name && def.catalogs.contains(&catalog))
+        {
+            Some(def) => def,
+            None => error::raise_with_hint(
+                PgSqlErrorCode::ERRCODE_FDW_INVALID_OPTION_NAME,
+                &format!("invalid option \"{}\"", name),
+                &valid_options_hint(catalog),
+            ),
+        };
+
+        if let Err(message) = check_value(def.kind, value) {
+            error::raise(
+                PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE,
+                &format!("invalid value for option \"{}\": {}", name, message),
+            );
+        }
+    }
+
+    if catalog == Catalog::Server {
+        let has = |option: &str| options.iter().any(|(name, _)| name == option);
+        if has("server_uri") && has("server_uris") {
+            error::raise(
+                PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE,
+                "server_uri and server_uris cannot be used together",
+            );
+        }
+
+        let has_tls = options.iter().any(|(name, _)| name.starts_with("tls_"));
+        let mut uris = options
+            .iter()
+            .filter(|(name, _)| name == "server_uri" || name == "server_uris")
+            .flat_map(|(_, value)| split_uris(value));
+        if has_tls && uris.any(|uri| !uri.starts_with("https://")) {
+            error::raise(
+                PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE,
+                "tls_* options require https:// server URIs",
+            );
+        }
+    }
+}
+
+fn valid_options_hint(catalog: Catalog) -> String {
+    let mut names: Vec<&str> = OPTIONS
+        .iter()
+        .filter(|def| def.catalogs.contains(&catalog))
+        .map(|def| def.name)
+        .collect();
+    if HEADER_CATALOGS.contains(&catalog) {
+        names.push("header.<key>");
+    }
+
+    if names.is_empty() {
+        String::from("There are no valid options in this context.")
+    } else {
+        format!("Valid options in this context are: {}", names.join(", "))
+    }
+}
+
+fn check_value(kind: Kind, value: &str) -> Result<(), String> {
+    match kind {
+        Kind::Uri => parse_uri(value).map(|_| ()),
+        Kind::UriList if split_uris(value).is_empty() => Err(String::from("must not be empty")),
+        Kind::UriList => split_uris(value)
+            .into_iter()
+            .try_for_each(|uri| parse_uri(&uri).map(|_| ())),
+        Kind::Bool => parse_bool(value).map(|_| ()),
+        Kind::Int { min, max } => parse_int(value, min, max).map(|_| ()),
+        Kind::Name if value.is_empty() => Err(String::from("must not be empty")),
+        Kind::Name => Ok(()),
+        Kind::Path if std::path::Path::new(value).is_absolute() => Ok(()),
+        Kind::Path => Err(format!("\"{}\" is not an absolute path", value)),
+        Kind::CodeList => crate::retry::parse_codes(value).map(|_| ()),
+        Kind::Method => crate::reflection::split_method(value).map(|_| ()),
+        Kind::Json => serde_json::from_str::<serde_json::Value>(value)
+            .map(|_| ())
+            .map_err(|e| format!("invalid JSON: {}", e)),
+        Kind::ShardMap => crate::shard::ShardMap::parse(value).map(|_| ()),
+        Kind::Enum(values) if values.contains(&value) => Ok(()),
+        Kind::Enum(values) => Err(format!("\"{}\" is not one of {}", value, values.join(", "))),
+    }
+}
+
+pub fn parse_uri(value: &str) -> Result<tonic::transport::Endpoint, String> {
+    if let Some(path) = crate::uds::socket_path(value) {
+        return if std::path::Path::new(path).is_absolute() {
+            Ok(crate::uds::endpoint())
+        } else {
+            Err(format!("\"{}\" must name an absolute socket path", value))
+        };
+    }
+
+    let endpoint = tonic::transport::Endpoint::from_shared(value.to_string())
+        .map_err(|e| format!("\"{}\" is not a valid URI: {}", value, e))?;
+
+    match endpoint.uri().scheme_str() {
+        Some("http") | Some("https") => Ok(endpoint),
+        _ => Err(format!(
+            "\"{}\" must use the http, https or unix scheme",
+            value
+        )),
+    }
+}
+
+/// Splits a comma separated `server_uris` value, ignoring blank entries.
+pub fn split_uris(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|uri| !uri.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+pub fn parse_bool(value: &str) -> Result<bool, String> {
+    match value.to_ascii_lowercase().as_str() {
+        "true" | "on" | "yes" | "1" => Ok(true),
+        "false" | "off" | "no" | "0" => Ok(false),
+        _ => Err(format!("\"{}\" is not a boolean", value)),
+    }
+}
+
+pub fn parse_int(value: &str, min: i64, max: i64) -> Result<i64, String> {
+    match value.trim().parse::<i64>() {
+        Ok(n) if n >= min && n <= max => Ok(n),
+        Ok(_) => Err(format!(
+            "{} is outside the valid range {}..{}",
+            value, min, max
+        )),
+        Err(_) => Err(format!("\"{}\" is not an integer", value)),
+    }
+}
+
+/// All options that apply to a foreign table, read from the catalogs.
+#[derive(Debug, Clone, Default)]
+pub struct ForeignOptions {
+    pub relid: pg_sys::Oid,
+    pub server_id: pg_sys::Oid,
+    pub server_name: String,
+    pub user_mapping_id: pg_sys::Oid,
+    /// Role the user mapping belongs to, `public` when it applies to everyone.
+    pub user_name: String,
+    pub server: HashMap<String, String>,
+    pub user_mapping: HashMap<String, String>,
+    pub table: HashMap<String, String>,
+    pub columns: HashMap<String, HashMap<String, String>>,
+}
+
+impl ForeignOptions {
+    pub fn for_table(namespace: &str, name: &str) -> Self {
+        let namespace = CString::new(namespace).unwrap();
+        let name = CString::new(name).unwrap();
+        let relid = unsafe {
+            let namespace = pg_sys::get_namespace_oid(namespace.as_ptr(), false);
+            pg_sys::get_relname_relid(name.as_ptr(), namespace)
+        };
+
+        Self::load(relid)
+    }
+
+    pub fn load(relid: pg_sys::Oid) -> Self {
+        unsafe {
+            let table = pg_sys::GetForeignTable(relid);
+            let server = pg_sys::GetForeignServer((*table).serverid);
+
+            Self {
+                relid,
+                table: options_map((*table).options),
+                columns: column_options(relid),
+                ..Self::from_server(server)
+            }
+        }
+    }
+
+    /// Options of a server on its own, for calls not tied to any table.
+    pub fn for_server(name: &str) -> Self {
+        let name = CString::new(name).unwrap();
+        unsafe { Self::from_server(pg_sys::GetForeignServerByName(name.as_ptr(), false)) }
+    }
+
+    unsafe fn from_server(server: *mut pg_sys::ForeignServer) -> Self {
+        let mapping = user_mapping(pg_sys::GetUserId(), (*server).serverid);
+        let user_name = match &mapping {
+            Some(mapping) if mapping.user != pg_sys::InvalidOid => {
+                CStr::from_ptr(pg_sys::GetUserNameFromId(mapping.user, false))
+                    .to_string_lossy()
+                    .into_owned()
+            }
+            _ => String::from("public"),
+        };
+
+        Self {
+            server_id: (*server).serverid,
+            server_name: CStr::from_ptr((*server).servername)
+                .to_string_lossy()
+                .into_owned(),
+            user_mapping_id: mapping.as_ref().map_or(pg_sys::InvalidOid, |m| m.id),
+            user_name,
+            server: options_map((*server).options),
+            user_mapping: mapping.map(|m| m.options).unwrap_or_default(),
+            ..Self::default()
+        }
+    }
+
+    /// The server's endpoints, from `server_uris` or the single `server_uri`.
+    pub fn server_uris(&self) -> Vec<String> {
+        match (
+            self.server.get("server_uris"),
+            self.server.get("server_uri"),
+        ) {
+            (Some(uris), _) => split_uris(uris),
+            (None, Some(uri)) => vec![uri.clone()],
+            (None, None) => error::raise(
+                PgSqlErrorCode::ERRCODE_FDW_OPTION_NAME_NOT_FOUND,
+                &format!(
+                    "server \"{}\" is missing the \"server_uri\" option",
+                    self.server_name
+                ),
+            ),
+        }
+    }
+
+    pub fn load_balancing(&self) -> crate::connection::Policy {
+        match self.server.get("load_balancing").map(String::as_str) {
+            Some("round_robin") => crate::connection::Policy::RoundRobin,
+            _ => crate::connection::Policy::PickFirst,
+        }
+    }
+
+    /// Name of the table on the remote side, defaulting to the local name.
+    pub fn remote_table<'a>(&'a self, local: &'a str) -> &'a str {
+        self.table
+            .get("table_name")
+            .map(String::as_str)
+            .unwrap_or(local)
+    }
+
+    /// Name of the column on the remote side, defaulting to the local name.
+    pub fn remote_column<'a>(&'a self, local: &'a str) -> &'a str {
+        self.columns
+            .get(local)
+            .and_then(|options| options.get("column_name"))
+            .map(String::as_str)
+            .unwrap_or(local)
+    }
+
+    /// Looks an option up on the table first, then on the server.
+    pub fn table_or_server(&self, name: &str) -> Option<&str> {
+        self.table
+            .get(name)
+            .or_else(|| self.server.get(name))
+            .map(String::as_str)
+    }
+
+    pub fn tls(&self, uri: &str) -> Option<crate::tls::TlsOptions> {
+        crate::tls::TlsOptions::from_options(uri, &self.server)
+    }
+
+    /// Method called for scans instead of `pg.Fdw/Execute`, if any.
+    pub fn method(&self) -> Option<&str> {
+        self.table.get("method").map(String::as_str)
+    }
+
+    /// JSON form of the request message sent to `method`.
+    pub fn request(&self) -> Option<&str> {
+        self.table.get("request").map(String::as_str)
+    }
+
+    /// Whether scans start their call as soon as the executor sets them up,
+    /// so that the scans under an `Append` wait for their servers together.
+    pub fn async_capable(&self) -> bool {
+        if self.method().is_some() || self.shard_key().is_some() {
+            return false;
+        }
+
+        self.table_or_server("async_capable")
+            .map(|value| parse_bool(value).unwrap_or(false))
+            .unwrap_or(false)
+    }
+
+    /// Parallel workers a scan may use on top of the leader, `0` when the
+    /// table is always scanned by a single process.
+    pub fn parallel_workers(&self) -> i32 {
+        if self.method().is_some() || self.shard_key().is_some() {
+            return 0;
+        }
+
+        self.table_or_server("parallel_workers")
+            .and_then(|value| parse_int(value, 0, 1024).ok())
+            .map_or(0, |workers| workers as i32)
+    }
+
+    /// Column whose value decides which of the server's endpoints holds a
+    /// row, for tables sharded over them.
+    pub fn shard_key(&self) -> Option<&str> {
+        if self.method().is_some() {
+            return None;
+        }
+
+        self.table.get("shard_key").map(String::as_str)
+    }
+
+    pub fn shard_map(&self) -> crate::shard::ShardMap {
+        self.table
+            .get("shard_map")
+            .and_then(|value| crate::shard::ShardMap::parse(value).ok())
+            .unwrap_or(crate::shard::ShardMap::Hash)
+    }
+
+    /// Compression and message size limits for calls to the server.
+    pub fn codec(&self) -> crate::codec::CodecOptions {
+        let size = |name| {
+            self.server
+                .get(name)
+                .and_then(|value| parse_int(value, 1, i32::MAX as i64).ok())
+                .map(|bytes| bytes as usize)
+        };
+
+        crate::codec::CodecOptions {
+            compression: match self.server.get("compression").map(String::as_str) {
+                Some("gzip") => Some(tonic::codec::CompressionEncoding::Gzip),
+                Some("zstd") => Some(tonic::codec::CompressionEncoding::Zstd),
+                _ => None,
+            },
+            max_decoding_message_size: size("max_decoding_message_size"),
+            max_encoding_message_size: size("max_encoding_message_size"),
+        }
+    }
+
+    /// Interval between HTTP/2 pings, also sent while the connection is
+    /// idle so that middleboxes do not drop it.
+    pub fn keepalive_interval(&self) -> Option<std::time::Duration> {
+        self.server
+            .get("keepalive_interval")
+            .and_then(|value| parse_int(value, 0, 86_400).ok())
+            .filter(|secs| *secs > 0)
+            .map(|secs| std::time::Duration::from_secs(secs as u64))
+    }
+
+    pub fn keepalive_timeout(&self) -> Option<std::time::Duration> {
+        self.server
+            .get("keepalive_timeout")
+            .and_then(|value| parse_int(value, 1, 3600).ok())
+            .map(|secs| std::time::Duration::from_secs(secs as u64))
+    }
+
+    pub fn initial_stream_window_size(&self) -> Option<u32> {
+        self.server
+            .get("initial_stream_window_size")
+            .and_then(|value| parse_int(value, 1, i32::MAX as i64).ok())
+            .map(|bytes| bytes as u32)
+    }
+
+    pub fn initial_connection_window_size(&self) -> Option<u32> {
+        self.server
+            .get("initial_connection_window_size")
+            .and_then(|value| parse_int(value, 1, i32::MAX as i64).ok())
+            .map(|bytes| bytes as u32)
+    }
+
+    /// Whether the session of the calling backend is sent as metadata, the
+    /// default.
+    pub fn session_metadata(&self) -> bool {
+        self.server
+            .get("session_metadata")
+            .map(|value| parse_bool(value).unwrap_or(true))
+            .unwrap_or(true)
+    }
+
+    /// `header.<key>` options of the server, table and user mapping, in
+    /// that order so that later ones take precedence.
+    pub fn headers(&self) -> Vec<(&str, &str)> {
+        let prefix = crate::metadata::HEADER_PREFIX;
+        [&self.server, &self.table, &self.user_mapping]
+            .iter()
+            .flat_map(|options| {
+                let mut headers: Vec<(&str, &str)> = options
+                    .iter()
+                    .filter_map(|(name, value)| {
+                        name.strip_prefix(prefix).map(|key| (key, value.as_str()))
+                    })
+                    .collect();
+                headers.sort_unstable();
+                headers
+            })
+            .collect()
+    }
+
+    /// Whether TCP_NODELAY is set on connections, the default.
+    pub fn tcp_nodelay(&self) -> bool {
+        self.server
+            .get("tcp_nodelay")
+            .map(|value| parse_bool(value).unwrap_or(true))
+            .unwrap_or(true)
+    }
+
+    /// How long an endpoint that failed is skipped, 30 seconds by default.
+    pub fn ejection_time(&self) -> std::time::Duration {
+        let secs = self
+            .server
+            .get("ejection_time")
+            .and_then(|value| parse_int(value, 1, 3600).ok())
+            .unwrap_or(30);
+
+        std::time::Duration::from_secs(secs as u64)
+    }
+
+    pub fn connect_timeout(&self) -> Option<std::time::Duration> {
+        self.server
+            .get("connect_timeout")
+            .and_then(|value| parse_int(value, 0, 3600).ok())
+            .filter(|secs| *secs > 0)
+            .map(|secs| std::time::Duration::from_secs(secs as u64))
+    }
+
+    /// Retry settings, each looked up on the table first, then on the server.
+    pub fn retry_policy(&self) -> crate::retry::RetryPolicy {
+        let defaults = crate::retry::RetryPolicy::default();
+        let millis = |name, max, default: std::time::Duration| {
+            self.table_or_server(name)
+                .and_then(|value| parse_int(value, 0, max).ok())
+                .map_or(default, |ms| std::time::Duration::from_millis(ms as u64))
+        };
+
+        crate::retry::RetryPolicy {
+            max_attempts: self
+                .table_or_server("retry_max_attempts")
+                .and_then(|value| parse_int(value, 1, 10).ok())
+                .map_or(defaults.max_attempts, |n| n as u32),
+            initial_backoff: millis("retry_initial_backoff", 60_000, defaults.initial_backoff),
+            max_backoff: millis("retry_max_backoff", 3_600_000, defaults.max_backoff),
+            codes: self
+                .table_or_server("retry_codes")
+                .and_then(|value| crate::retry::parse_codes(value).ok())
+                .unwrap_or(defaults.codes),
+        }
+    }
+
+    pub fn request_timeout(&self) -> Option<std::time::Duration> {
+        self.table_or_server("request_timeout")
+            .and_then(|value| parse_int(value, 0, i32::MAX as i64).ok())
+            .filter(|ms| *ms > 0)
+            .map(|ms| std::time::Duration::from_millis(ms as u64))
+    }
+}
+
+unsafe fn options_map(list: *mut pg_sys::List) -> HashMap<String, String> {
+    PgList::<pg_sys::DefElem>::from_pg(list)
+        .iter_ptr()
+        .map(|def| {
+            let name = CStr::from_ptr((*def).defname)
+                .to_string_lossy()
+                .into_owned();
+            let value = CStr::from_ptr(pg_sys::defGetString(def))
+                .to_string_lossy()
+                .into_owned();
+
+            (name, value)
+        })
+        .collect()
+}
+
+unsafe fn column_options(relid: pg_sys::Oid) -> HashMap<String, HashMap<String, String>> {
+    let rel = pg_sys::RelationIdGetRelation(relid);
+    let desc = PgTupleDesc::from_pg_unchecked((*rel).rd_att);
+    let columns = desc
+        .iter()
+        .filter(|attr| !attr.is_dropped())
+        .map(|attr| {
+            let options = options_map(pg_sys::GetForeignColumnOptions(relid, attr.attnum));
+            (attr.name().to_string(), options)
+        })
+        .filter(|(_, options)| !options.is_empty())
+        .collect();
+    pg_sys::RelationClose(rel);
+
+    columns
+}
+
+/// The user mapping for `user` on `server`, falling back to the PUBLIC one.
+/// Unlike `GetUserMapping`, a missing mapping is not an error since grpc_fdw
+/// does not require one.
+unsafe fn user_mapping(user: pg_sys::Oid, server: pg_sys::Oid) -> Option<UserMapping> {
+    for user in [user, pg_sys::InvalidOid].iter() {
+        let tuple = pg_sys::SearchSysCache2(
+            pg_sys::SysCacheIdentifier_USERMAPPINGUSERSERVER as i32,
+            user.into_datum().unwrap(),
+            server.into_datum().unwrap(),
+        );
+        if tuple.is_null() {
+            continue;
+        }
+
+        let form = pg_sys::heap_tuple_get_struct::<pg_sys::FormData_pg_user_mapping>(tuple);
+        let mut is_null = false;
+        let datum = pg_sys::SysCacheGetAttr(
+            pg_sys::SysCacheIdentifier_USERMAPPINGUSERSERVER as i32,
+            tuple,
+            pg_sys::Anum_pg_user_mapping_umoptions as i16,
+            &mut is_null,
+        );
+        let options = if is_null {
+            HashMap::new()
+        } else {
+            options_map(pg_sys::untransformRelOptions(datum))
+        };
+        let mapping = UserMapping {
+            id: (*form).oid,
+            user: *user,
+            options,
+        };
+        pg_sys::ReleaseSysCache(tuple);
+
+        return Some(mapping);
+    }
+
+    None
+}
+
+struct UserMapping {
+    id: pg_sys::Oid,
+    user: pg_sys::Oid,
+    options: HashMap<String, String>,
+}
@@ -0,0 +1,380 @@
+use crate::client::Client;
+use crate::options::ForeignOptions;
+use pgx::*;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::os::raw::c_void;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// Fixed cost of a scan, as postgres_fdw's default.
+const STARTUP_COST: f64 = 100.0;
+
+/// Receiving and decoding a row costs far more than reading a local tuple,
+/// which is what makes spreading it over workers worthwhile.
+const ROW_COST: f64 = 1.0;
+
+/// Rows assumed for partitions without an estimate.
+const DEFAULT_PARTITION_ROWS: f64 = 1000.0;
+
+thread_local! {
+    /// Partitions shared by the participants of each parallel scan node.
+    static SCANS: RefCell<HashMap<usize, Rc<SharedPartitions>>> = RefCell::new(HashMap::new());
+
+    /// Scan node being iterated, for `GRPCFdw::execute` to find its partitions.
+    static CURRENT: Cell<usize> = Cell::new(0);
+
+    static CALLBACK_REGISTERED: Cell<bool> = Cell::new(false);
+
+    static GET_FOREIGN_PATHS: Cell<pg_sys::GetForeignPaths_function> = Cell::new(None);
+
+    static GET_FOREIGN_PLAN: Cell<pg_sys::GetForeignPlan_function> = Cell::new(None);
+
+    static ITERATE_FOREIGN_SCAN: Cell<pg_sys::IterateForeignScan_function> = Cell::new(None);
+
+    static END_FOREIGN_SCAN: Cell<pg_sys::EndForeignScan_function> = Cell::new(None);
+}
+
+/// Lets tables with `parallel_workers` set be scanned by parallel workers.
+/// The server splits the scan into partitions with `PlanPartitions` while
+/// planning. They are kept in the plan, the leader writes them to shared
+/// memory and every participant then claims one at a time until none are
+/// left.
+pub fn install(routine: &mut PgBox<pg_sys::FdwRoutine>) {
+    GET_FOREIGN_PATHS.with(|f| f.set(routine.GetForeignPaths));
+    GET_FOREIGN_PLAN.with(|f| f.set(routine.GetForeignPlan));
+    ITERATE_FOREIGN_SCAN.with(|f| f.set(routine.IterateForeignScan));
+    END_FOREIGN_SCAN.with(|f| f.set(routine.EndForeignScan));
+    routine.GetForeignPaths = Some(get_foreign_paths);
+    routine.GetForeignPlan = Some(get_foreign_plan);
+    routine.IterateForeignScan = Some(iterate_foreign_scan);
+    routine.EndForeignScan = Some(end_foreign_scan);
+    routine.IsForeignScanParallelSafe = Some(is_foreign_scan_parallel_safe);
+    routine.EstimateDSMForeignScan = Some(estimate_dsm_foreign_scan);
+    routine.InitializeDSMForeignScan = Some(initialize_dsm_foreign_scan);
+    routine.ReinitializeDSMForeignScan = Some(reinitialize_dsm_foreign_scan);
+    routine.InitializeWorkerForeignScan = Some(initialize_worker_foreign_scan);
+}
+
+#[pg_guard]
+unsafe extern "C" fn is_foreign_scan_parallel_safe(
+    _root: *mut pg_sys::PlannerInfo,
+    _rel: *mut pg_sys::RelOptInfo,
+    rte: *mut pg_sys::RangeTblEntry,
+) -> bool {
+    ForeignOptions::load((*rte).relid).parallel_workers() > 0
+}
+
+/// Adds a partial path next to the regular ones, which are left as they
+/// are, when the server splits the table into at least two partitions. It is
+/// costed from the rows the server expects, the planner then only goes
+/// parallel for large tables. Partitions are only planned when the planner
+/// considers a partial path at all, and servers failing to plan them are
+/// scanned serially.
+#[pg_guard]
+unsafe extern "C" fn get_foreign_paths(
+    root: *mut pg_sys::PlannerInfo,
+    baserel: *mut pg_sys::RelOptInfo,
+    foreigntableid: pg_sys::Oid,
+) {
+    if let Some(get_paths) = GET_FOREIGN_PATHS.with(Cell::get) {
+        get_paths(root, baserel, foreigntableid);
+    }
+    if !(*baserel).consider_parallel || pg_sys::max_parallel_workers_per_gather <= 0 {
+        return;
+    }
+
+    let options = ForeignOptions::load(foreigntableid);
+    let workers = options
+        .parallel_workers()
+        .min(pg_sys::max_parallel_workers_per_gather);
+    if workers <= 0 {
+        return;
+    }
+
+    let rel = pg_sys::RelationIdGetRelation(foreigntableid);
+    let desc = PgTupleDesc::from_pg_unchecked((*rel).rd_att);
+    let (table, request) = crate::scan_request(rel, &desc, &options);
+    pg_sys::RelationClose(rel);
+    let partitions = match Client::for_table(&options, table).plan_partitions(request) {
+        Ok(partitions) if partitions.len() >= 2 => partitions,
+        _ => return,
+    };
+
+    let rows: f64 = partitions
+        .iter()
+        .map(|partition| match partition.rows {
+            rows if rows > 0.0 => rows,
+            _ => DEFAULT_PARTITION_ROWS,
+        })
+        .sum();
+
+    let paths = PgList::<pg_sys::Path>::from_pg((*baserel).pathlist);
+    let fdw_private = paths
+        .iter_ptr()
+        .find(|path| (**path).type_ == pg_sys::NodeTag_T_ForeignPath)
+        .map_or(std::ptr::null_mut(), |path| {
+            (*(path as *mut pg_sys::ForeignPath)).fdw_private
+        });
+
+    // The partial path carries its partitions, so the scan runs the very
+    // ones it was costed from.
+    let tokens: Vec<Vec<u8>> = partitions
+        .into_iter()
+        .map(|partition| partition.token)
+        .collect();
+    let planned = pg_sys::makeConst(
+        pg_sys::BYTEAOID,
+        -1,
+        pg_sys::InvalidOid,
+        -1,
+        encode(&tokens).into_datum().unwrap(),
+        false,
+        false,
+    );
+    let fdw_private = pg_sys::lappend(pg_sys::list_copy(fdw_private), planned as *mut c_void);
+
+    let workers = workers.min(tokens.len() as i32);
+    let divisor = parallel_divisor(workers);
+    let path = pg_sys::create_foreignscan_path(
+        root,
+        baserel,
+        std::ptr::null_mut(),
+        rows / divisor,
+        STARTUP_COST,
+        STARTUP_COST + rows * ROW_COST / divisor,
+        std::ptr::null_mut(),
+        std::ptr::null_mut(),
+        std::ptr::null_mut(),
+        fdw_private,
+    );
+    (*path).path.parallel_aware = true;
+    (*path).path.parallel_workers = workers;
+    pg_sys::add_partial_path(baserel, path as *mut pg_sys::Path);
+}
+
+/// Keeps the partitions of a partial path in the plan made from it.
+#[pg_guard]
+unsafe extern "C" fn get_foreign_plan(
+    root: *mut pg_sys::PlannerInfo,
+    baserel: *mut pg_sys::RelOptInfo,
+    foreigntableid: pg_sys::Oid,
+    best_path: *mut pg_sys::ForeignPath,
+    tlist: *mut pg_sys::List,
+    scan_clauses: *mut pg_sys::List,
+    outer_plan: *mut pg_sys::Plan,
+) -> *mut pg_sys::ForeignScan {
+    let get_plan = GET_FOREIGN_PLAN
+        .with(Cell::get)
+        .unwrap_or_else(|| error!("grpc_fdw: GetForeignPlan is missing"));
+    let plan = get_plan(
+        root,
+        baserel,
+        foreigntableid,
+        best_path,
+        tlist,
+        scan_clauses,
+        outer_plan,
+    );
+
+    if (*best_path).path.parallel_aware {
+        let planned = last((*best_path).fdw_private);
+        if !planned.is_null() && last((*plan).fdw_private) != planned {
+            (*plan).fdw_private = pg_sys::lappend((*plan).fdw_private, planned as *mut c_void);
+        }
+    }
+
+    plan
+}
+
+unsafe fn last(list: *mut pg_sys::List) -> *mut pg_sys::Node {
+    let list = PgList::<pg_sys::Node>::from_pg(list);
+    match list.len() {
+        0 => std::ptr::null_mut(),
+        len => list.get_ptr(len - 1).unwrap_or(std::ptr::null_mut()),
+    }
+}
+
+/// The shared memory layout of the partitions the scan was planned with,
+/// see [`encode`].
+unsafe fn planned(node: *mut pg_sys::ForeignScanState) -> Vec<u8> {
+    let plan = (*node).ss.ps.plan as *mut pg_sys::ForeignScan;
+    let planned = last((*plan).fdw_private);
+    if planned.is_null() || (*planned).type_ != pg_sys::NodeTag_T_Const {
+        error!("grpc_fdw: parallel scan was not planned")
+    }
+
+    let planned = planned as *mut pg_sys::Const;
+    Vec::<u8>::from_datum(
+        (*planned).constvalue,
+        (*planned).constisnull,
+        pg_sys::BYTEAOID,
+    )
+    .unwrap_or_else(|| error!("grpc_fdw: parallel scan was not planned"))
+}
+
+/// Share of the rows each participant handles, with the leader contributing
+/// less the more workers it has to look after, as the planner assumes.
+fn parallel_divisor(workers: i32) -> f64 {
+    let leader = 1.0 - 0.3 * workers as f64;
+    workers as f64 + leader.max(0.0)
+}
+
+#[pg_guard]
+unsafe extern "C" fn estimate_dsm_foreign_scan(
+    node: *mut pg_sys::ForeignScanState,
+    _pcxt: *mut pg_sys::ParallelContext,
+) -> pg_sys::Size {
+    planned(node).len()
+}
+
+#[pg_guard]
+unsafe extern "C" fn initialize_dsm_foreign_scan(
+    node: *mut pg_sys::ForeignScanState,
+    _pcxt: *mut pg_sys::ParallelContext,
+    coordinate: *mut c_void,
+) {
+    let shared = planned(node);
+    std::ptr::copy_nonoverlapping(shared.as_ptr(), coordinate as *mut u8, shared.len());
+
+    attach(node, coordinate);
+}
+
+#[pg_guard]
+unsafe extern "C" fn reinitialize_dsm_foreign_scan(
+    _node: *mut pg_sys::ForeignScanState,
+    _pcxt: *mut pg_sys::ParallelContext,
+    coordinate: *mut c_void,
+) {
+    (*(coordinate as *const AtomicU32)).store(0, Ordering::SeqCst);
+}
+
+#[pg_guard]
+unsafe extern "C" fn initialize_worker_foreign_scan(
+    node: *mut pg_sys::ForeignScanState,
+    _toc: *mut pg_sys::shm_toc,
+    coordinate: *mut c_void,
+) {
+    attach(node, coordinate);
+}
+
+unsafe fn attach(node: *mut pg_sys::ForeignScanState, coordinate: *mut c_void) {
+    register_callback();
+    let size = *(coordinate as *const u32).add(1) as usize;
+    let shared = std::slice::from_raw_parts(coordinate as *const u8, size);
+    let partitions = SharedPartitions {
+        next: coordinate as *const AtomicU32,
+        tokens: decode(shared),
+    };
+
+    SCANS.with(|scans| {
+        scans
+            .borrow_mut()
+            .insert(node as usize, Rc::new(partitions))
+    });
+}
+
+#[pg_guard]
+unsafe extern "C" fn iterate_foreign_scan(
+    node: *mut pg_sys::ForeignScanState,
+) -> *mut pg_sys::TupleTableSlot {
+    let iterate = ITERATE_FOREIGN_SCAN
+        .with(Cell::get)
+        .unwrap_or_else(|| error!("grpc_fdw: IterateForeignScan is missing"));
+
+    let previous = CURRENT.with(|current| current.replace(node as usize));
+    let slot = iterate(node);
+    CURRENT.with(|current| current.set(previous));
+
+    slot
+}
+
+#[pg_guard]
+unsafe extern "C" fn end_foreign_scan(node: *mut pg_sys::ForeignScanState) {
+    SCANS.with(|scans| scans.borrow_mut().remove(&(node as usize)));
+
+    if let Some(end) = END_FOREIGN_SCAN.with(Cell::get) {
+        end(node);
+    }
+}
+
+/// Partitions of the parallel scan being iterated, `None` outside of one.
+pub fn current() -> Option<Rc<SharedPartitions>> {
+    let node = CURRENT.with(Cell::get);
+    SCANS.with(|scans| scans.borrow().get(&node).cloned())
+}
+
+/// The scan node being iterated, null outside of `IterateForeignScan`.
+pub fn current_node() -> *mut pg_sys::ForeignScanState {
+    CURRENT.with(Cell::get) as *mut pg_sys::ForeignScanState
+}
+
+/// Partition tokens in shared memory, along with the index of the next one
+/// to be claimed.
+pub struct SharedPartitions {
+    next: *const AtomicU32,
+    tokens: Vec<Vec<u8>>,
+}
+
+impl SharedPartitions {
+    /// Claims the next partition no participant has scanned yet.
+    pub fn claim(&self) -> Option<Vec<u8>> {
+        let index = unsafe { (*self.next).fetch_add(1, Ordering::SeqCst) };
+        self.tokens.get(index as usize).cloned()
+    }
+}
+
+/// Lays out partition tokens for shared memory: the index of the next one
+/// to claim, the total size, their count and lengths, then the tokens.
+fn encode(tokens: &[Vec<u8>]) -> Vec<u8> {
+    let mut header = vec![0, 0, tokens.len() as u32];
+    header.extend(tokens.iter().map(|token| token.len() as u32));
+
+    let mut shared: Vec<u8> = header.iter().flat_map(|n| n.to_ne_bytes()).collect();
+    for token in tokens {
+        shared.extend_from_slice(token);
+    }
+    let size = (shared.len() as u32).to_ne_bytes();
+    shared[4..8].copy_from_slice(&size);
+
+    shared
+}
+
+fn decode(shared: &[u8]) -> Vec<Vec<u8>> {
+    let word = |i: usize| {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&shared[i * 4..i * 4 + 4]);
+        u32::from_ne_bytes(bytes) as usize
+    };
+
+    let count = word(2);
+    let mut offset = (3 + count) * 4;
+    (0..count)
+        .map(|i| {
+            let len = word(3 + i);
+            let token = shared[offset..offset + len].to_vec();
+            offset += len;
+            token
+        })
+        .collect()
+}
+
+fn register_callback() {
+    if !CALLBACK_REGISTERED.with(Cell::get) {
+        unsafe { pg_sys::RegisterXactCallback(Some(clear), std::ptr::null_mut()) };
+        CALLBACK_REGISTERED.with(|registered| registered.set(true));
+    }
+}
+
+/// Forgets the scans of a transaction, scans that failed do not get to
+/// `EndForeignScan` and their shared memory is gone.
+#[pg_guard]
+unsafe extern "C" fn clear(event: pg_sys::XactEvent, _arg: *mut c_void) {
+    if event == pg_sys::XactEvent_XACT_EVENT_COMMIT
+        || event == pg_sys::XactEvent_XACT_EVENT_ABORT
+        || event == pg_sys::XactEvent_XACT_EVENT_PARALLEL_COMMIT
+        || event == pg_sys::XactEvent_XACT_EVENT_PARALLEL_ABORT
+    {
+        SCANS.with(|scans| scans.borrow_mut().clear());
+    }
+}
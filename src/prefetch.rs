@@ -0,0 +1,143 @@
+use crate::client::pg::ExecuteRequest;
+use crate::client::{Client, PendingExecute};
+use crate::options::ForeignOptions;
+use pgx::*;
+use std::cell::{Cell, RefCell};
+
+thread_local! {
+    /// Calls started by scans that have not run yet, with the scan node and
+    /// subtransaction they were started in.
+    static PENDING: RefCell<Vec<(usize, pg_sys::SubTransactionId, PendingExecute)>> = RefCell::new(Vec::new());
+
+    static CALLBACK_REGISTERED: Cell<bool> = Cell::new(false);
+
+    static BEGIN_FOREIGN_SCAN: Cell<pg_sys::BeginForeignScan_function> = Cell::new(None);
+
+    static END_FOREIGN_SCAN: Cell<pg_sys::EndForeignScan_function> = Cell::new(None);
+}
+
+/// Wraps the scan callbacks of `routine` so that scans of `async_capable`
+/// tables start their call in `BeginForeignScan`. The executor sets up every
+/// child of an `Append` before running the first one, so their calls are all
+/// in flight by the time the results of the first are needed.
+pub fn install(routine: &mut PgBox<pg_sys::FdwRoutine>) {
+    BEGIN_FOREIGN_SCAN.with(|f| f.set(routine.BeginForeignScan));
+    END_FOREIGN_SCAN.with(|f| f.set(routine.EndForeignScan));
+    routine.BeginForeignScan = Some(begin_foreign_scan);
+    routine.EndForeignScan = Some(end_foreign_scan);
+}
+
+#[pg_guard]
+unsafe extern "C" fn begin_foreign_scan(node: *mut pg_sys::ForeignScanState, eflags: i32) {
+    if let Some(begin) = BEGIN_FOREIGN_SCAN.with(Cell::get) {
+        begin(node, eflags);
+    }
+    // Parallel scans call once per partition, see `parallel`.
+    if eflags & pg_sys::EXEC_FLAG_EXPLAIN_ONLY as i32 != 0 || (*(*node).ss.ps.plan).parallel_aware {
+        return;
+    }
+
+    let rel = (*node).ss.ss_currentRelation;
+    let options = ForeignOptions::load((*rel).rd_id);
+    if !options.async_capable() {
+        return;
+    }
+
+    register_callback();
+    // The rows are decoded into the scan slot, whose descriptor is the one
+    // `execute` builds its request from and must find unchanged in `take`.
+    let desc = PgTupleDesc::from_pg_unchecked((*(*node).ss.ss_ScanTupleSlot).tts_tupleDescriptor);
+    let (table, request) = crate::scan_request(rel, &desc, &options);
+    let client = Client::for_table(&options, table);
+    if let Some(pending) = client.start_execute(request) {
+        let subxact = pg_sys::GetCurrentSubTransactionId();
+        PENDING.with(|p| p.borrow_mut().push((node as usize, subxact, pending)));
+    }
+}
+
+#[pg_guard]
+unsafe extern "C" fn end_foreign_scan(node: *mut pg_sys::ForeignScanState) {
+    // Scans stopped before fetching a row, under a `LIMIT` for example,
+    // never picked up their call.
+    PENDING.with(|p| {
+        let mut pending = p.borrow_mut();
+        if let Some(index) = pending.iter().position(|(n, ..)| *n == node as usize) {
+            pending.remove(index).2.cancel();
+        }
+    });
+
+    if let Some(end) = END_FOREIGN_SCAN.with(Cell::get) {
+        end(node);
+    }
+}
+
+/// Takes the call started for scan `node`, if it asked for the same as
+/// `request`.
+pub fn take(
+    node: *mut pg_sys::ForeignScanState,
+    request: &ExecuteRequest,
+) -> Option<PendingExecute> {
+    let call = PENDING.with(|p| {
+        let mut pending = p.borrow_mut();
+        let index = pending.iter().position(|(n, ..)| *n == node as usize)?;
+
+        Some(pending.remove(index).2)
+    })?;
+    if call.request() != request {
+        call.cancel();
+        return None;
+    }
+
+    Some(call)
+}
+
+fn register_callback() {
+    if !CALLBACK_REGISTERED.with(Cell::get) {
+        unsafe {
+            pg_sys::RegisterXactCallback(Some(clear), std::ptr::null_mut());
+            pg_sys::RegisterSubXactCallback(Some(clear_subxact), std::ptr::null_mut());
+        }
+        CALLBACK_REGISTERED.with(|registered| registered.set(true));
+    }
+}
+
+/// Cancels whatever is left once a transaction ends, however it does, scans
+/// that failed do not get to `EndForeignScan`.
+#[pg_guard]
+unsafe extern "C" fn clear(event: pg_sys::XactEvent, _arg: *mut std::os::raw::c_void) {
+    if event == pg_sys::XactEvent_XACT_EVENT_COMMIT
+        || event == pg_sys::XactEvent_XACT_EVENT_ABORT
+        || event == pg_sys::XactEvent_XACT_EVENT_PARALLEL_COMMIT
+        || event == pg_sys::XactEvent_XACT_EVENT_PARALLEL_ABORT
+        || event == pg_sys::XactEvent_XACT_EVENT_PREPARE
+    {
+        let pending = PENDING.with(|p| std::mem::take(&mut *p.borrow_mut()));
+        for (.., call) in pending {
+            call.cancel();
+        }
+    }
+}
+
+/// Cancels the calls of a rolled back subtransaction and of those nested in
+/// it, whose scan nodes are gone along with its memory.
+#[pg_guard]
+unsafe extern "C" fn clear_subxact(
+    event: pg_sys::SubXactEvent,
+    subxact: pg_sys::SubTransactionId,
+    _parent: pg_sys::SubTransactionId,
+    _arg: *mut std::os::raw::c_void,
+) {
+    if event == pg_sys::SubXactEvent_SUBXACT_EVENT_ABORT_SUB {
+        let aborted = PENDING.with(|p| {
+            let mut pending = p.borrow_mut();
+            let (aborted, kept): (Vec<_>, Vec<_>) = std::mem::take(&mut *pending)
+                .into_iter()
+                .partition(|(_, started, _)| *started >= subxact);
+            *pending = kept;
+            aborted
+        });
+        for (.., call) in aborted {
+            call.cancel();
+        }
+    }
+}
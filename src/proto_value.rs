@@ -1,8 +1,11 @@
+use crate::temporal;
+use base64::Engine;
 use pgx::*;
 use prost_types::value::*;
 use prost_types::Value;
 use serde_json::Value as JsonValue;
 use std::collections::BTreeMap;
+use std::ffi::CStr;
 
 pub struct ProtoValue(pub prost_types::Value);
 
@@ -10,7 +13,12 @@ impl<'a> From<&'a JsonValue> for ProtoValue {
     fn from(json: &'a JsonValue) -> Self {
         let kind = match json {
             JsonValue::Null => Kind::NullValue(0),
-            JsonValue::Number(num) => Kind::NumberValue(num.as_f64().unwrap()),
+            // Numbers without an f64 form are sent as text, as bigints beyond
+            // 2^53 are.
+            JsonValue::Number(num) => match num.as_f64() {
+                Some(n) => Kind::NumberValue(n),
+                None => Kind::StringValue(num.to_string()),
+            },
             JsonValue::String(str) => Kind::StringValue(str.clone()),
             JsonValue::Bool(bool) => Kind::BoolValue(bool.clone()),
             JsonValue::Array(arr) => {
@@ -41,38 +49,129 @@ impl ProtoValue {
         }
     }
 
+    /// Encodes a column value for the server. Booleans, numbers and JSON keep
+    /// their type, dates and times are sent in ISO 8601 form, `bytea` as
+    /// base64 and anything else as its type's output function prints it.
     pub fn from_tuple(
         oid: &pgx::PgBuiltInOids,
         datum: &Option<pg_sys::Datum>,
         typeoid: &PgOid,
     ) -> Self {
-        match oid {
+        let raw = match datum {
+            Some(raw) => *raw,
+            None => return Self::kind(Kind::NullValue(0)),
+        };
+
+        let kind = match oid {
             PgBuiltInOids::JSONBOID => {
                 let JsonB(v) = Self::from_datum::<JsonB>(datum, typeoid).unwrap();
 
-                Self::from(&v)
+                return Self::from(&v);
             }
-            PgBuiltInOids::TEXTOID => {
-                let v = Self::from_datum::<String>(datum, typeoid).unwrap();
+            PgBuiltInOids::JSONOID => {
+                let Json(v) = Self::from_datum::<Json>(datum, typeoid).unwrap();
 
-                let value = prost_types::Value {
-                    kind: Some(Kind::StringValue(v)),
-                };
+                return Self::from(&v);
+            }
+            PgBuiltInOids::BOOLOID => {
+                Kind::BoolValue(Self::from_datum::<bool>(datum, typeoid).unwrap())
+            }
+            PgBuiltInOids::INT2OID => {
+                Kind::NumberValue(Self::from_datum::<i16>(datum, typeoid).unwrap().into())
+            }
+            PgBuiltInOids::INT4OID => {
+                Kind::NumberValue(Self::from_datum::<i32>(datum, typeoid).unwrap().into())
+            }
+            PgBuiltInOids::INT8OID => int8(Self::from_datum::<i64>(datum, typeoid).unwrap()),
+            PgBuiltInOids::FLOAT4OID => {
+                float(Self::from_datum::<f32>(datum, typeoid).unwrap().into())
+            }
+            PgBuiltInOids::FLOAT8OID => float(Self::from_datum::<f64>(datum, typeoid).unwrap()),
+            PgBuiltInOids::TEXTOID | PgBuiltInOids::VARCHAROID | PgBuiltInOids::BPCHAROID => {
+                Kind::StringValue(Self::from_datum::<String>(datum, typeoid).unwrap())
+            }
+            PgBuiltInOids::BYTEAOID => {
+                let bytes = Self::from_datum::<Vec<u8>>(datum, typeoid).unwrap();
 
-                Self(value)
+                Kind::StringValue(base64::engine::general_purpose::STANDARD.encode(bytes))
             }
-            PgBuiltInOids::INT8OID | PgBuiltInOids::INT4OID => {
-                let v = Self::from_datum::<i32>(datum, typeoid).unwrap();
-                let value = prost_types::Value {
-                    kind: Some(Kind::NumberValue(v.into())),
-                };
+            PgBuiltInOids::DATEOID => Kind::StringValue(temporal::date(raw as i32)),
+            PgBuiltInOids::TIMEOID => Kind::StringValue(temporal::time(raw as i64)),
+            PgBuiltInOids::TIMETZOID => {
+                let time = unsafe { &*(raw as *const pg_sys::TimeTzADT) };
 
-                Self(value)
+                Kind::StringValue(temporal::timetz(time.time, time.zone))
             }
-            pg_oid => {
-                warning!("Unsupported OID {:?}", pg_oid);
-                error!("FIXME");
+            PgBuiltInOids::TIMESTAMPOID => Kind::StringValue(temporal::timestamp(raw as i64)),
+            PgBuiltInOids::TIMESTAMPTZOID => Kind::StringValue(temporal::timestamptz(raw as i64)),
+            PgBuiltInOids::INTERVALOID => {
+                let interval = unsafe { &*(raw as *const pg_sys::Interval) };
+
+                Kind::StringValue(temporal::interval(
+                    interval.month,
+                    interval.day,
+                    interval.time,
+                ))
+            }
+            PgBuiltInOids::UUIDOID => {
+                Kind::StringValue(uuid(unsafe { &*(raw as *const [u8; 16]) }))
             }
+            // numeric keeps its exact digits this way, and name, inet, cidr,
+            // macaddr, money, enums and the like have no better form.
+            _ => Kind::StringValue(unsafe { output(raw, typeoid.value()) }),
+        };
+
+        Self::kind(kind)
+    }
+
+    fn kind(kind: Kind) -> Self {
+        Self(prost_types::Value { kind: Some(kind) })
+    }
+}
+
+/// Numbers beyond 2^53 would lose digits as a double, they are sent as text.
+fn int8(n: i64) -> Kind {
+    const EXACT: i64 = 1 << f64::MANTISSA_DIGITS;
+
+    if (-EXACT..=EXACT).contains(&n) {
+        Kind::NumberValue(n as f64)
+    } else {
+        Kind::StringValue(n.to_string())
+    }
+}
+
+/// `NaN` and the infinities have no JSON number form, they are sent as the
+/// strings protobuf's JSON mapping uses.
+fn float(n: f64) -> Kind {
+    if n.is_nan() {
+        Kind::StringValue(String::from("NaN"))
+    } else if n.is_infinite() {
+        let sign = if n < 0.0 { "-" } else { "" };
+        Kind::StringValue(format!("{}Infinity", sign))
+    } else {
+        Kind::NumberValue(n)
+    }
+}
+
+fn uuid(bytes: &[u8; 16]) -> String {
+    let mut s = String::with_capacity(36);
+    for (i, byte) in bytes.iter().enumerate() {
+        if matches!(i, 4 | 6 | 8 | 10) {
+            s.push('-');
         }
+        s.push_str(&format!("{:02x}", byte));
     }
+
+    s
+}
+
+/// The text form of a value, as its type's output function writes it.
+pub unsafe fn output(datum: pg_sys::Datum, typoid: pg_sys::Oid) -> String {
+    let mut output = 0;
+    let mut varlena = false;
+    pg_sys::getTypeOutputInfo(typoid, &mut output, &mut varlena);
+
+    CStr::from_ptr(pg_sys::OidOutputFunctionCall(output, datum))
+        .to_string_lossy()
+        .into_owned()
 }
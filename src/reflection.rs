@@ -0,0 +1,127 @@
+use prost::Message;
+use prost_reflect::{DescriptorPool, MethodDescriptor};
+use prost_types::FileDescriptorProto;
+use std::collections::HashMap;
+use tonic::metadata::MetadataMap;
+use tonic::transport::Channel;
+use tonic::Status;
+use v1alpha::server_reflection_client::ServerReflectionClient;
+use v1alpha::server_reflection_request::MessageRequest;
+use v1alpha::server_reflection_response::MessageResponse;
+use v1alpha::ServerReflectionRequest;
+
+mod v1alpha {
+    tonic::include_proto!("grpc.reflection.v1alpha");
+}
+
+pub const RPC: &str = "grpc.reflection.v1alpha.ServerReflection/ServerReflectionInfo";
+
+/// Resolves `method` through server reflection, fetching the file that
+/// declares its service and everything that file imports.
+pub async fn resolve(
+    channel: Channel,
+    method: &str,
+    metadata: &MetadataMap,
+) -> Result<MethodDescriptor, Status> {
+    let (service, _) = split_method(method).map_err(Status::invalid_argument)?;
+    let mut client = ServerReflectionClient::new(channel);
+
+    let mut files: HashMap<String, FileDescriptorProto> = HashMap::new();
+    let mut pending = vec![MessageRequest::FileContainingSymbol(service.to_string())];
+    while let Some(request) = pending.pop() {
+        if let MessageRequest::FileByFilename(name) = &request {
+            if files.contains_key(name) {
+                continue;
+            }
+        }
+
+        for file in lookup(&mut client, request, metadata).await? {
+            pending.extend(
+                file.dependency
+                    .iter()
+                    .filter(|name| !files.contains_key(*name))
+                    .map(|name| MessageRequest::FileByFilename(name.clone())),
+            );
+            files.insert(file.name().to_string(), file);
+        }
+    }
+
+    let mut pool = DescriptorPool::new();
+    pool.add_file_descriptor_protos(files.into_values())
+        .map_err(|e| {
+            Status::internal(format!("invalid descriptors from server reflection: {}", e))
+        })?;
+
+    find_method(&pool, method)
+}
+
+async fn lookup(
+    client: &mut ServerReflectionClient<Channel>,
+    request: MessageRequest,
+    metadata: &MetadataMap,
+) -> Result<Vec<FileDescriptorProto>, Status> {
+    let request = ServerReflectionRequest {
+        host: String::new(),
+        message_request: Some(request),
+    };
+    let mut request = tonic::Request::new(tokio_stream::once(request));
+    *request.metadata_mut() = metadata.clone();
+    let mut stream = client.server_reflection_info(request).await?.into_inner();
+    let response = stream
+        .message()
+        .await?
+        .ok_or_else(|| Status::unknown("server reflection sent no response"))?;
+
+    match response.message_response {
+        Some(MessageResponse::FileDescriptorResponse(response)) => response
+            .file_descriptor_proto
+            .iter()
+            .map(|bytes| {
+                FileDescriptorProto::decode(&bytes[..])
+                    .map_err(|e| Status::internal(format!("invalid file descriptor: {}", e)))
+            })
+            .collect(),
+        Some(MessageResponse::ErrorResponse(error)) => Err(Status::new(
+            tonic::Code::from_i32(error.error_code),
+            error.error_message,
+        )),
+        _ => Err(Status::unknown("unexpected server reflection response")),
+    }
+}
+
+/// Reads a serialized `FileDescriptorSet`, as written by
+/// `protoc --include_imports --descriptor_set_out`.
+pub fn load_descriptor_set(path: &str) -> Result<DescriptorPool, Status> {
+    let bytes = std::fs::read(path).map_err(|e| {
+        Status::failed_precondition(format!("could not read descriptor_set \"{}\": {}", path, e))
+    })?;
+
+    DescriptorPool::decode(&bytes[..]).map_err(|e| {
+        Status::failed_precondition(format!("invalid descriptor_set \"{}\": {}", path, e))
+    })
+}
+
+/// Looks `pkg.Service/Method` up in a descriptor pool.
+pub fn find_method(pool: &DescriptorPool, method: &str) -> Result<MethodDescriptor, Status> {
+    let (service, name) = split_method(method).map_err(Status::invalid_argument)?;
+    let descriptor = pool
+        .get_service_by_name(service)
+        .ok_or_else(|| Status::not_found(format!("service \"{}\" not found", service)))?;
+
+    let found = descriptor.methods().find(|m| m.name() == name);
+    found.ok_or_else(|| Status::not_found(format!("method \"{}\" not found", method)))
+}
+
+/// Splits `pkg.Service/Method`, also accepting the `/pkg.Service/Method`
+/// form of a gRPC request path.
+pub fn split_method(method: &str) -> Result<(&str, &str), String> {
+    match method.trim_start_matches('/').split_once('/') {
+        Some((service, name)) if !service.is_empty() && !name.is_empty() && !name.contains('/') => {
+            Ok((service, name))
+        }
+        _ => Err(format!(
+            "\"{}\" is not of the form package.Service/Method",
+            method
+        )),
+    }
+}
@@ -0,0 +1,84 @@
+use std::time::Duration;
+use tonic::Code;
+
+/// Status codes that may be listed in `retry_codes`, by their gRPC names.
+const CODES: &[(&str, Code)] = &[
+    ("CANCELLED", Code::Cancelled),
+    ("UNKNOWN", Code::Unknown),
+    ("INVALID_ARGUMENT", Code::InvalidArgument),
+    ("DEADLINE_EXCEEDED", Code::DeadlineExceeded),
+    ("NOT_FOUND", Code::NotFound),
+    ("ALREADY_EXISTS", Code::AlreadyExists),
+    ("PERMISSION_DENIED", Code::PermissionDenied),
+    ("RESOURCE_EXHAUSTED", Code::ResourceExhausted),
+    ("FAILED_PRECONDITION", Code::FailedPrecondition),
+    ("ABORTED", Code::Aborted),
+    ("OUT_OF_RANGE", Code::OutOfRange),
+    ("UNIMPLEMENTED", Code::Unimplemented),
+    ("INTERNAL", Code::Internal),
+    ("UNAVAILABLE", Code::Unavailable),
+    ("DATA_LOSS", Code::DataLoss),
+    ("UNAUTHENTICATED", Code::Unauthenticated),
+];
+
+/// When and how often a failed call is repeated.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Total number of attempts, `1` disables retries.
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub codes: Vec<Code>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 1,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            codes: vec![Code::Unavailable],
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Whether a call that failed with `code` on attempt `attempt` (counting
+    /// from 1) should be tried again.
+    pub fn should_retry(&self, attempt: u32, code: Code) -> bool {
+        attempt < self.max_attempts && self.codes.contains(&code)
+    }
+
+    /// Delay before the attempt following `attempt`, doubling every time up
+    /// to `max_backoff`.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.initial_backoff
+            .checked_mul(factor)
+            .map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff))
+    }
+}
+
+/// Parses a comma separated list of gRPC status code names.
+pub fn parse_codes(value: &str) -> Result<Vec<Code>, String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            CODES
+                .iter()
+                .find(|(code_name, _)| code_name.eq_ignore_ascii_case(name))
+                .map(|(_, code)| *code)
+                .ok_or_else(|| format!("\"{}\" is not a gRPC status code", name))
+        })
+        .collect()
+}
+
+/// The gRPC name of a status code, as used in `retry_codes`.
+pub fn code_name(code: Code) -> &'static str {
+    CODES
+        .iter()
+        .find(|(_, known)| *known == code)
+        .map_or("OK", |(name, _)| name)
+}
@@ -1,22 +1,57 @@
+use crate::error;
 use pgx::*;
 use prost_types::value::*;
+use prost_types::ListValue;
 use serde_json::map::Map;
 use serde_json::Value as JsonValue;
+use std::ffi::{CStr, CString};
+
+/// A column of the foreign table, which result values are decoded into.
+pub struct Column {
+    typoid: pg_sys::Oid,
+    typmod: i32,
+    /// Names the column in conversion errors.
+    context: String,
+}
+
+/// The columns of `desc`, `None` for dropped ones.
+pub fn columns(desc: &PgTupleDesc, table: &str) -> Vec<Option<Column>> {
+    desc.iter()
+        .map(|attr| {
+            if attr.is_dropped() {
+                return None;
+            }
+
+            Some(Column {
+                typoid: attr.type_oid().value(),
+                typmod: attr.atttypmod,
+                context: format!("column \"{}\" of foreign table \"{}\"", attr.name(), table),
+            })
+        })
+        .collect()
+}
 
 impl crate::client::pg::ResultSet {
-    pub fn into_datums(self) -> Vec<Option<pg_sys::Datum>> {
+    pub fn into_datums(self, columns: &[Option<Column>]) -> Vec<Option<pg_sys::Datum>> {
         self.values
             .into_iter()
-            .map(Self::value_into_datum)
+            .zip(columns)
+            .map(|(value, column)| {
+                let column = column.as_ref()?;
+                error::with_context(&column.context, || Self::value_into_datum(value, column))
+            })
             .collect()
     }
 
-    fn into_json(value: &prost_types::Value) -> serde_json::Value {
+    pub fn into_json(value: &prost_types::Value) -> serde_json::Value {
         match &value.kind {
             Some(Kind::NullValue(_)) => JsonValue::Null,
-            Some(Kind::NumberValue(n)) => {
-                JsonValue::Number(serde_json::Number::from_f64(n.to_owned()).expect(""))
-            }
+            // JSON has no NaN or infinities, they become the strings writes
+            // send them as.
+            Some(Kind::NumberValue(n)) => match serde_json::Number::from_f64(*n) {
+                Some(num) => JsonValue::Number(num),
+                None => JsonValue::String(number(*n)),
+            },
             Some(Kind::StringValue(string)) => JsonValue::String(String::from(string)),
             Some(Kind::BoolValue(boolean)) => JsonValue::Bool(boolean.to_owned()),
             Some(Kind::StructValue(sv)) => {
@@ -34,25 +69,168 @@ impl crate::client::pg::ResultSet {
         }
     }
 
-    fn value_into_datum(value: prost_types::Value) -> Option<pg_sys::Datum> {
-        match value.kind {
-            Some(Kind::StringValue(str)) => str.into_datum(),
-            Some(Kind::NullValue(_)) => None,
-            Some(Kind::BoolValue(boolean)) => boolean.into_datum(),
-            Some(Kind::NumberValue(n)) => n.into_datum(),
-            Some(Kind::StructValue(sv)) => {
-                let mut obj = Map::new();
-                for (key, value) in sv.fields.iter() {
-                    obj.insert(String::from(key), Self::into_json(&value));
-                }
+    /// Decodes `value` as the column's type. Values of other kinds, and types
+    /// without a direct mapping, go through the type's input function.
+    fn value_into_datum(value: prost_types::Value, column: &Column) -> Option<pg_sys::Datum> {
+        let kind = match value.kind {
+            None | Some(Kind::NullValue(_)) => return None,
+            Some(kind) => kind,
+        };
 
-                pgx::JsonB(JsonValue::Object(obj)).into_datum()
+        match (column.typoid, kind) {
+            (pg_sys::BOOLOID, Kind::BoolValue(boolean)) => boolean.into_datum(),
+            (pg_sys::INT2OID, Kind::NumberValue(n)) => {
+                (column.integer(n, i16::MIN.into(), i16::MAX.into()) as i16).into_datum()
+            }
+            (pg_sys::INT4OID, Kind::NumberValue(n)) => {
+                (column.integer(n, i32::MIN.into(), i32::MAX.into()) as i32).into_datum()
+            }
+            (pg_sys::INT8OID, Kind::NumberValue(n)) => {
+                column.integer(n, i64::MIN, i64::MAX).into_datum()
+            }
+            (pg_sys::FLOAT4OID, Kind::NumberValue(n)) => {
+                if n.is_finite() && n.abs() > f32::MAX.into() {
+                    column.mismatch(&number(n), "out of range");
+                }
+                (n as f32).into_datum()
+            }
+            (pg_sys::FLOAT8OID, Kind::NumberValue(n)) => n.into_datum(),
+            (pg_sys::TEXTOID, Kind::StringValue(string)) => string.into_datum(),
+            (pg_sys::JSONOID, kind) => {
+                pgx::Json(Self::into_json(&prost_types::Value { kind: Some(kind) })).into_datum()
+            }
+            (pg_sys::JSONBOID, kind) => {
+                pgx::JsonB(Self::into_json(&prost_types::Value { kind: Some(kind) })).into_datum()
+            }
+            (pg_sys::BYTEAOID, Kind::StringValue(string)) => {
+                use base64::Engine;
+                match base64::engine::general_purpose::STANDARD.decode(&string) {
+                    Ok(bytes) => bytes.into_datum(),
+                    Err(_) => column.mismatch(&format!("\"{}\"", string), "not base64"),
+                }
+            }
+            (typoid, Kind::ListValue(list))
+                if unsafe { pg_sys::get_element_type(typoid) } != pg_sys::InvalidOid =>
+            {
+                column.array(list)
+            }
+            (_, Kind::StringValue(string)) => column.input(&string),
+            (_, Kind::NumberValue(n)) => column.input(&number(n)),
+            (_, Kind::BoolValue(boolean)) => column.input(&boolean.to_string()),
+            (_, kind) => {
+                column.input(&Self::into_json(&prost_types::Value { kind: Some(kind) }).to_string())
             }
-            //Some(Kind::ListValue(list)) => {
-            //    Value::Array(list.values.iter().map(|v| as_json(&v.kind)).collect())
-            //}
-            None => None,
-            _ => None,
         }
     }
 }
+
+impl Column {
+    fn integer(&self, n: f64, min: i64, max: i64) -> i64 {
+        integer(n, min, max).unwrap_or_else(|reason| self.mismatch(&number(n), reason))
+    }
+
+    /// Decodes each element of `list` as the column's element type.
+    fn array(&self, list: ListValue) -> Option<pg_sys::Datum> {
+        let element = Column {
+            typoid: unsafe { pg_sys::get_element_type(self.typoid) },
+            typmod: self.typmod,
+            context: String::new(),
+        };
+        if list.values.is_empty() {
+            return Some(unsafe { pg_sys::construct_empty_array(element.typoid) } as pg_sys::Datum);
+        }
+
+        let (mut datums, mut nulls): (Vec<pg_sys::Datum>, Vec<bool>) = list
+            .values
+            .into_iter()
+            .map(
+                |value| match crate::client::pg::ResultSet::value_into_datum(value, &element) {
+                    Some(datum) => (datum, false),
+                    None => (0, true),
+                },
+            )
+            .unzip();
+
+        unsafe {
+            let mut len = 0;
+            let mut byval = false;
+            let mut align = 0;
+            pg_sys::get_typlenbyvalalign(element.typoid, &mut len, &mut byval, &mut align);
+
+            let mut dims = [datums.len() as i32];
+            let mut lbs = [1];
+            let array = pg_sys::construct_md_array(
+                datums.as_mut_ptr(),
+                nulls.as_mut_ptr(),
+                1,
+                dims.as_mut_ptr(),
+                lbs.as_mut_ptr(),
+                element.typoid,
+                len as i32,
+                byval,
+                align,
+            );
+
+            Some(array as pg_sys::Datum)
+        }
+    }
+
+    /// Parses `text` with the type's input function, which raises its own
+    /// errors for text that does not fit.
+    fn input(&self, text: &str) -> Option<pg_sys::Datum> {
+        let text = match CString::new(text) {
+            Ok(text) => text,
+            Err(_) => self.mismatch(&format!("{:?}", text), "contains a NUL byte"),
+        };
+
+        unsafe {
+            let mut infunc = pg_sys::InvalidOid;
+            let mut typioparam = pg_sys::InvalidOid;
+            pg_sys::getTypeInputInfo(self.typoid, &mut infunc, &mut typioparam);
+
+            Some(pg_sys::OidInputFunctionCall(
+                infunc,
+                text.as_ptr() as *mut _,
+                typioparam,
+                self.typmod,
+            ))
+        }
+    }
+
+    fn mismatch(&self, value: &str, reason: &str) -> ! {
+        let typname = unsafe { CStr::from_ptr(pg_sys::format_type_be(self.typoid)) };
+        error::raise(
+            PgSqlErrorCode::ERRCODE_FDW_INVALID_DATA_TYPE,
+            &format!(
+                "cannot convert {} to type {}: {}",
+                value,
+                typname.to_string_lossy(),
+                reason
+            ),
+        )
+    }
+}
+
+/// `n` as an integer between `min` and `max`, or why it is not one.
+fn integer(n: f64, min: i64, max: i64) -> Result<i64, &'static str> {
+    if n.fract() != 0.0 {
+        return Err("not an integer");
+    }
+    // `max as f64` of an i64 rounds up to 2^63, itself out of range.
+    if n < min as f64 || n > max as f64 || (max == i64::MAX && n >= max as f64) {
+        return Err("out of range");
+    }
+
+    Ok(n as i64)
+}
+
+/// The text form of `n` Postgres parses, integers without a fraction.
+fn number(n: f64) -> String {
+    if n.is_nan() {
+        String::from("NaN")
+    } else if n.is_infinite() {
+        String::from(if n > 0.0 { "Infinity" } else { "-Infinity" })
+    } else {
+        n.to_string()
+    }
+}
@@ -0,0 +1,336 @@
+use crate::error;
+use crate::options::ForeignOptions;
+use pgx::*;
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+use std::ffi::{CStr, CString};
+
+/// How the rows of a sharded table are spread over the endpoints of its
+/// server, the `shard_map` option.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ShardMap {
+    /// By the 64-bit FNV-1a hash of the key's text form, modulo the number
+    /// of endpoints.
+    Hash,
+    /// By the first of these ascending bounds the key is below, keys above
+    /// all of them belong to the last endpoint.
+    Range(Vec<String>),
+}
+
+impl ShardMap {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let value = value.trim();
+        if value == "hash" {
+            return Ok(ShardMap::Hash);
+        }
+
+        let bounds: Vec<String> = value
+            .strip_prefix("range:")
+            .ok_or_else(|| format!("\"{}\" is neither hash nor range:<bounds>", value))?
+            .split(',')
+            .map(|bound| bound.trim().to_string())
+            .collect();
+        if bounds.iter().any(String::is_empty) {
+            return Err(String::from("range bounds must not be empty"));
+        }
+        if bounds
+            .windows(2)
+            .any(|pair| compare(&pair[0], &pair[1]) != Ordering::Less)
+        {
+            return Err(String::from("range bounds must be ascending"));
+        }
+
+        Ok(ShardMap::Range(bounds))
+    }
+
+    /// Checks that the map covers exactly `shards` endpoints.
+    pub fn check(&self, shards: usize) -> Result<(), String> {
+        match self {
+            ShardMap::Range(bounds) if bounds.len() + 1 != shards => Err(format!(
+                "{} range bounds need {} endpoints, the server has {}",
+                bounds.len(),
+                bounds.len() + 1,
+                shards
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// The endpoint owning `key`, out of `shards`.
+    pub fn shard(&self, key: &str, shards: usize) -> usize {
+        match self {
+            ShardMap::Hash => (fnv1a(key.as_bytes()) % shards as u64) as usize,
+            ShardMap::Range(bounds) => bounds
+                .iter()
+                .position(|bound| compare(key, bound) == Ordering::Less)
+                .unwrap_or(bounds.len()),
+        }
+    }
+}
+
+/// Compares as numbers when both sides are numbers, as text otherwise.
+fn compare(a: &str, b: &str) -> Ordering {
+    match (a.parse::<f64>(), b.parse::<f64>()) {
+        (Ok(a), Ok(b)) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+        _ => a.cmp(b),
+    }
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// A table whose rows are spread over the endpoints of its server by the
+/// value of its `shard_key` column.
+#[derive(Debug, Clone)]
+pub struct Sharding {
+    key: String,
+    map: ShardMap,
+    shards: usize,
+}
+
+impl Sharding {
+    /// `None` for tables without a `shard_key`.
+    pub fn load(options: &ForeignOptions) -> Option<Self> {
+        let key = options.shard_key()?.to_string();
+        let name = CString::new(key.as_str()).unwrap();
+        if unsafe { pg_sys::get_attnum(options.relid, name.as_ptr()) } == 0 {
+            error::raise(
+                PgSqlErrorCode::ERRCODE_FDW_INVALID_ATTRIBUTE_VALUE,
+                &format!("invalid shard_key: the table has no column \"{}\"", key),
+            )
+        }
+        let map = options.shard_map();
+        let shards = options.server_uris().len();
+        if let Err(message) = map.check(shards) {
+            error::raise(
+                PgSqlErrorCode::ERRCODE_FDW_INVALID_ATTRIBUTE_VALUE,
+                &format!("invalid shard_map: {}", message),
+            )
+        }
+
+        Some(Self { key, map, shards })
+    }
+
+    pub fn all(&self) -> Vec<usize> {
+        (0..self.shards).collect()
+    }
+
+    /// The endpoint a new row goes to.
+    pub fn insert(&self, row: &[pgx_fdw::Tuple]) -> usize {
+        self.owner(row).unwrap_or_else(|| {
+            error::raise(
+                PgSqlErrorCode::ERRCODE_NOT_NULL_VIOLATION,
+                &format!("shard key \"{}\" must not be null", self.key),
+            )
+        })
+    }
+
+    /// The endpoints an `UPDATE` goes to: the owner of the row, or all of
+    /// them when neither `indices` nor `row` include its key. Rows cannot
+    /// change owner, so a new key needs the old one to check against.
+    pub fn update(&self, row: &[pgx_fdw::Tuple], indices: &[pgx_fdw::Tuple]) -> Vec<usize> {
+        match (self.owner(indices), self.owner(row)) {
+            (Some(owner), Some(new_owner)) if owner != new_owner => error::raise(
+                PgSqlErrorCode::ERRCODE_FEATURE_NOT_SUPPORTED,
+                &format!(
+                    "updating shard key \"{}\" cannot move a row to another shard",
+                    self.key
+                ),
+            ),
+            (Some(owner), _) => vec![owner],
+            (None, Some(_)) => error::raise(
+                PgSqlErrorCode::ERRCODE_FEATURE_NOT_SUPPORTED,
+                &format!(
+                    "updating shard key \"{}\" needs it among the row identifying columns",
+                    self.key
+                ),
+            ),
+            (None, None) => self.all(),
+        }
+    }
+
+    /// The endpoints a `DELETE` goes to: the owner of the row, or all of
+    /// them when `indices` do not include its key.
+    pub fn delete(&self, indices: &[pgx_fdw::Tuple]) -> Vec<usize> {
+        match self.owner(indices) {
+            Some(owner) => vec![owner],
+            None => self.all(),
+        }
+    }
+
+    /// The endpoint owning the row `tuples` belong to, `None` if they do not
+    /// include its key or the key is NULL.
+    fn owner(&self, tuples: &[pgx_fdw::Tuple]) -> Option<usize> {
+        let (_, datum, typeoid) = tuples.iter().find(|(name, ..)| *name == self.key)?;
+        let key = unsafe { text(datum.as_ref().copied()?, typeoid.value()) };
+
+        Some(self.map.shard(&key, self.shards))
+    }
+
+    /// The endpoints a scan needs: those owning the values its quals compare
+    /// the key to with `=` or `IN`, or all of them.
+    pub unsafe fn scan(&self, node: *mut pg_sys::ForeignScanState) -> Vec<usize> {
+        match self.route(node) {
+            Some((owners, _)) => owners.into_iter().collect(),
+            None => self.all(),
+        }
+    }
+
+    /// The quals [`Sharding::scan`] picks the endpoints by.
+    pub unsafe fn scan_quals(&self, node: *mut pg_sys::ForeignScanState) -> Vec<*mut pg_sys::Node> {
+        self.route(node).map_or_else(Vec::new, |(_, quals)| quals)
+    }
+
+    /// The endpoints owning the keys the quals of the scan allow, along with
+    /// those quals, `None` if none of them restrict the key.
+    unsafe fn route(
+        &self,
+        node: *mut pg_sys::ForeignScanState,
+    ) -> Option<(BTreeSet<usize>, Vec<*mut pg_sys::Node>)> {
+        if node.is_null() {
+            return None;
+        }
+
+        let desc = PgTupleDesc::from_pg_unchecked((*(*node).ss.ss_currentRelation).rd_att);
+        let attr = desc
+            .iter()
+            .find(|attr| !attr.is_dropped() && attr.name() == self.key)?;
+
+        let mut owners: Option<BTreeSet<usize>> = None;
+        let mut used = Vec::new();
+        let quals = PgList::<pg_sys::Node>::from_pg((*(*node).ss.ps.plan).qual);
+        for qual in quals.iter_ptr() {
+            if let Some(keys) = key_values(qual, attr.attnum, attr.type_oid().value()) {
+                let shards: BTreeSet<usize> = keys
+                    .iter()
+                    .map(|key| self.map.shard(key, self.shards))
+                    .collect();
+                owners = Some(match owners {
+                    Some(owners) => owners.intersection(&shards).copied().collect(),
+                    None => shards,
+                });
+                used.push(qual);
+            }
+        }
+
+        owners.map(|owners| (owners, used))
+    }
+}
+
+/// The key values `qual` restricts column `attnum` to, if it is an `=` or
+/// `IN` against constants of the column's own type.
+unsafe fn key_values(
+    qual: *mut pg_sys::Node,
+    attnum: i16,
+    typoid: pg_sys::Oid,
+) -> Option<Vec<String>> {
+    match (*qual).type_ {
+        pg_sys::NodeTag_T_OpExpr => {
+            let op = qual as *mut pg_sys::OpExpr;
+            let args = PgList::<pg_sys::Node>::from_pg((*op).args);
+            if args.len() != 2 || !is_equality((*op).opno) {
+                return None;
+            }
+
+            let (left, right) = (args.get_ptr(0)?, args.get_ptr(1)?);
+            let value = if is_key(left, attnum) {
+                right
+            } else if is_key(right, attnum) {
+                left
+            } else {
+                return None;
+            };
+            let value = constant(value, typoid)?;
+
+            // `key = NULL` matches no row at all.
+            Some(value.into_iter().map(|datum| text(datum, typoid)).collect())
+        }
+        pg_sys::NodeTag_T_ScalarArrayOpExpr => {
+            let op = qual as *mut pg_sys::ScalarArrayOpExpr;
+            let args = PgList::<pg_sys::Node>::from_pg((*op).args);
+            if args.len() != 2 || !(*op).useOr || !is_equality((*op).opno) {
+                return None;
+            }
+            if !is_key(args.get_ptr(0)?, attnum) {
+                return None;
+            }
+
+            let array = constant(args.get_ptr(1)?, pg_sys::get_array_type(typoid))?;
+            Some(match array {
+                Some(array) => elements(array, typoid),
+                None => Vec::new(),
+            })
+        }
+        _ => None,
+    }
+}
+
+unsafe fn is_equality(opno: pg_sys::Oid) -> bool {
+    let name = pg_sys::get_opname(opno);
+    !name.is_null() && CStr::from_ptr(name).to_bytes() == b"="
+}
+
+/// Whether `node` is column `attnum`, possibly relabelled to a binary
+/// compatible type.
+unsafe fn is_key(mut node: *mut pg_sys::Node, attnum: i16) -> bool {
+    while (*node).type_ == pg_sys::NodeTag_T_RelabelType {
+        node = (*(node as *mut pg_sys::RelabelType)).arg as *mut pg_sys::Node;
+    }
+
+    (*node).type_ == pg_sys::NodeTag_T_Var && (*(node as *mut pg_sys::Var)).varattno == attnum
+}
+
+/// The value of a constant of type `typoid`, `Some(None)` if it is NULL.
+unsafe fn constant(node: *mut pg_sys::Node, typoid: pg_sys::Oid) -> Option<Option<pg_sys::Datum>> {
+    if (*node).type_ != pg_sys::NodeTag_T_Const {
+        return None;
+    }
+
+    let constant = node as *mut pg_sys::Const;
+    if (*constant).consttype != typoid {
+        return None;
+    }
+
+    Some(if (*constant).constisnull {
+        None
+    } else {
+        Some((*constant).constvalue)
+    })
+}
+
+/// The non-NULL elements of an array of `typoid`.
+unsafe fn elements(array: pg_sys::Datum, typoid: pg_sys::Oid) -> Vec<String> {
+    let array = pg_sys::pg_detoast_datum(array as *mut pg_sys::varlena) as *mut pg_sys::ArrayType;
+    let mut len = 0;
+    let mut byval = false;
+    let mut align = 0;
+    pg_sys::get_typlenbyvalalign(typoid, &mut len, &mut byval, &mut align);
+
+    let mut datums = std::ptr::null_mut();
+    let mut nulls = std::ptr::null_mut();
+    let mut count = 0;
+    pg_sys::deconstruct_array(
+        array,
+        typoid,
+        len as i32,
+        byval,
+        align,
+        &mut datums,
+        &mut nulls,
+        &mut count,
+    );
+
+    (0..count as usize)
+        .filter(|i| !*nulls.add(*i))
+        .map(|i| text(*datums.add(i), typoid))
+        .collect()
+}
+
+/// Keys are routed by their text form, so scans and writes agree whatever
+/// the type.
+unsafe fn text(datum: pg_sys::Datum, typoid: pg_sys::Oid) -> String {
+    crate::proto_value::output(datum, typoid)
+}
@@ -0,0 +1,456 @@
+use crate::retry;
+use pgx::*;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tonic::Code;
+
+/// Server, table, RPC and status combinations counted, calls of any further
+/// ones are left out of the statistics.
+const MAX_ENTRIES: usize = 1024;
+
+/// Statistics of every backend and parallel worker, when loaded through
+/// `shared_preload_libraries`.
+static SHARED_STATS: PgLwLock<StatsTable> = PgLwLock::new();
+
+thread_local! {
+    /// Whether `SHARED_STATS` was set up, each backend counts its own calls
+    /// in `LOCAL_STATS` otherwise.
+    static SHARED: Cell<bool> = Cell::new(false);
+
+    static LOCAL_STATS: RefCell<Box<StatsTable>> = RefCell::new(StatsTable::boxed());
+
+    /// Totals of the scans running, by scan node.
+    static SCANS: RefCell<HashMap<usize, ScanTotals>> = RefCell::new(HashMap::new());
+
+    static BEGIN_FOREIGN_SCAN: Cell<pg_sys::BeginForeignScan_function> = Cell::new(None);
+
+    static END_FOREIGN_SCAN: Cell<pg_sys::EndForeignScan_function> = Cell::new(None);
+}
+
+/// Sets up the statistics in shared memory, only possible while the server
+/// loads `shared_preload_libraries`.
+pub fn init() {
+    if unsafe { pg_sys::process_shared_preload_libraries_in_progress } {
+        pg_shmem_init!(SHARED_STATS);
+        SHARED.with(|shared| shared.set(true));
+    }
+}
+
+fn update_stats<R>(f: impl FnOnce(&mut StatsTable) -> R) -> R {
+    if SHARED.with(Cell::get) {
+        f(&mut SHARED_STATS.exclusive())
+    } else {
+        LOCAL_STATS.with(|stats| f(&mut stats.borrow_mut()))
+    }
+}
+
+fn read_stats<R>(f: impl FnOnce(&StatsTable) -> R) -> R {
+    if SHARED.with(Cell::get) {
+        f(&SHARED_STATS.share())
+    } else {
+        LOCAL_STATS.with(|stats| f(&stats.borrow()))
+    }
+}
+
+/// Wraps the scan callbacks of `routine` so that every scan node counts its
+/// own calls, however many scans of the same table a query has.
+pub fn install(routine: &mut PgBox<pg_sys::FdwRoutine>) {
+    BEGIN_FOREIGN_SCAN.with(|f| f.set(routine.BeginForeignScan));
+    END_FOREIGN_SCAN.with(|f| f.set(routine.EndForeignScan));
+    routine.BeginForeignScan = Some(begin_foreign_scan);
+    routine.EndForeignScan = Some(end_foreign_scan);
+}
+
+#[pg_guard]
+unsafe extern "C" fn begin_foreign_scan(node: *mut pg_sys::ForeignScanState, eflags: i32) {
+    SCANS.with(|scans| {
+        scans
+            .borrow_mut()
+            .insert(node as usize, ScanTotals::default())
+    });
+    if let Some(begin) = BEGIN_FOREIGN_SCAN.with(Cell::get) {
+        begin(node, eflags);
+    }
+}
+
+/// `EXPLAIN ANALYZE` has read the totals by now, the executor ends scans
+/// after the plan is printed.
+#[pg_guard]
+unsafe extern "C" fn end_foreign_scan(node: *mut pg_sys::ForeignScanState) {
+    if let Some(end) = END_FOREIGN_SCAN.with(Cell::get) {
+        end(node);
+    }
+    SCANS.with(|scans| scans.borrow_mut().remove(&(node as usize)));
+}
+
+/// What the calls of one scan node added up to since it began, for
+/// `EXPLAIN ANALYZE`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ScanTotals {
+    pub calls: u64,
+    pub rows_received: u64,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+    pub time: Duration,
+}
+
+pub fn scan_totals(node: *mut pg_sys::ForeignScanState) -> ScanTotals {
+    SCANS.with(|scans| {
+        scans
+            .borrow()
+            .get(&(node as usize))
+            .copied()
+            .unwrap_or_default()
+    })
+}
+
+/// Statistics are kept per table, RPC and outcome.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+struct StatsKey {
+    server: Name,
+    table: Name,
+    rpc: Name,
+    code: i32,
+}
+
+/// A name stored inline so that it can live in shared memory, cut short at
+/// 127 bytes.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Name {
+    len: u8,
+    bytes: [u8; 127],
+}
+
+impl Name {
+    fn new(name: &str) -> Self {
+        let mut len = name.len().min(127);
+        while !name.is_char_boundary(len) {
+            len -= 1;
+        }
+
+        let mut bytes = [0; 127];
+        bytes[..len].copy_from_slice(&name.as_bytes()[..len]);
+        Self {
+            len: len as u8,
+            bytes,
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        std::str::from_utf8(&self.bytes[..self.len as usize]).unwrap_or_default()
+    }
+}
+
+impl Default for Name {
+    fn default() -> Self {
+        Self::new("")
+    }
+}
+
+impl std::fmt::Debug for Name {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.as_str().fmt(f)
+    }
+}
+
+/// A fixed number of entries, the same in shared and in backend memory.
+#[derive(Clone, Copy)]
+struct StatsTable {
+    len: usize,
+    entries: [(StatsKey, Stats); MAX_ENTRIES],
+}
+
+unsafe impl PGXSharedMemory for StatsTable {}
+
+/// Only used by `pg_shmem_init!`, once in the postmaster, backends build
+/// their own table with [`StatsTable::boxed`].
+impl Default for StatsTable {
+    fn default() -> Self {
+        Self {
+            len: 0,
+            entries: [Default::default(); MAX_ENTRIES],
+        }
+    }
+}
+
+impl StatsTable {
+    /// An empty table allocated on the heap right away, it is too large to
+    /// be built on the stack first.
+    fn boxed() -> Box<Self> {
+        let layout = std::alloc::Layout::new::<Self>();
+        unsafe {
+            // All zeroes are an empty table: no entries, empty names and
+            // zero counts and durations.
+            let table = std::alloc::alloc_zeroed(layout) as *mut Self;
+            if table.is_null() {
+                std::alloc::handle_alloc_error(layout)
+            }
+
+            Box::from_raw(table)
+        }
+    }
+
+    /// The statistics of `key`, `None` if it is new and the table is full.
+    fn entry(&mut self, key: StatsKey) -> Option<&mut Stats> {
+        let index = match self.entries[..self.len].iter().position(|(k, _)| *k == key) {
+            Some(index) => index,
+            None if self.len < MAX_ENTRIES => {
+                self.entries[self.len] = (key, Stats::default());
+                self.len += 1;
+                self.len - 1
+            }
+            None => return None,
+        };
+
+        Some(&mut self.entries[index].1)
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &(StatsKey, Stats)> {
+        self.entries[..self.len].iter()
+    }
+
+    fn retain(&mut self, keep: impl Fn(&StatsKey) -> bool) {
+        let mut kept = 0;
+        for index in 0..self.len {
+            if keep(&self.entries[index].0) {
+                self.entries[kept] = self.entries[index];
+                kept += 1;
+            }
+        }
+        self.len = kept;
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Stats {
+    calls: u64,
+    rows_sent: u64,
+    bytes_sent: u64,
+    rows_received: u64,
+    bytes_received: u64,
+    connects: u64,
+    connect_time: Duration,
+    first_rows: u64,
+    first_row_time: Duration,
+    latency: Histogram,
+}
+
+/// Latencies counted in buckets of doubling width, the first one up to a
+/// millisecond and the last one unbounded.
+#[derive(Debug, Clone, Copy, Default)]
+struct Histogram {
+    buckets: [u64; 20],
+    count: u64,
+    total: Duration,
+    max: Duration,
+}
+
+impl Histogram {
+    fn record(&mut self, latency: Duration) {
+        let index = (0..self.buckets.len() - 1)
+            .find(|i| latency <= Self::upper_bound(*i))
+            .unwrap_or(self.buckets.len() - 1);
+        self.buckets[index] += 1;
+        self.count += 1;
+        self.total += latency;
+        self.max = self.max.max(latency);
+    }
+
+    fn upper_bound(index: usize) -> Duration {
+        Duration::from_millis(1 << index)
+    }
+
+    /// The upper bound of the bucket holding the `q` quantile, capped at the
+    /// largest latency seen.
+    fn quantile(&self, q: f64) -> Duration {
+        let rank = (q * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (index, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Self::upper_bound(index).min(self.max);
+            }
+        }
+
+        self.max
+    }
+
+    fn mean(&self) -> Duration {
+        match self.count {
+            0 => Duration::ZERO,
+            n => Duration::from_nanos((self.total.as_nanos() / n as u128) as u64),
+        }
+    }
+}
+
+/// What a call sends, the same for every attempt.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sent {
+    pub rows: u64,
+    pub bytes: usize,
+}
+
+/// One attempt of a call, filled in as messages arrive and recorded once it
+/// finished.
+#[derive(Debug)]
+pub struct Attempt {
+    started: Instant,
+    rows_sent: u64,
+    bytes_sent: u64,
+    first_row: Cell<Option<Instant>>,
+    rows_received: Cell<u64>,
+    bytes_received: Cell<u64>,
+}
+
+impl Attempt {
+    pub fn start(sent: Sent) -> Self {
+        Self {
+            started: Instant::now(),
+            rows_sent: sent.rows,
+            bytes_sent: sent.bytes as u64,
+            first_row: Cell::new(None),
+            rows_received: Cell::new(0),
+            bytes_received: Cell::new(0),
+        }
+    }
+
+    /// When the first row arrived, for calls that ran in the background.
+    pub fn first_row_at(&self, at: Instant) {
+        self.first_row.set(Some(at));
+    }
+
+    pub fn received(&self, bytes: usize) {
+        if self.first_row.get().is_none() {
+            self.first_row.set(Some(Instant::now()));
+        }
+        self.rows_received.set(self.rows_received.get() + 1);
+        self.bytes_received
+            .set(self.bytes_received.get() + bytes as u64);
+    }
+
+    pub fn finish(
+        &self,
+        server: &str,
+        table: &str,
+        rpc: &str,
+        code: Code,
+        connect_time: Option<Duration>,
+    ) {
+        let key = StatsKey {
+            server: Name::new(server),
+            table: Name::new(table),
+            rpc: Name::new(rpc),
+            code: code as i32,
+        };
+
+        // Calls of modifications and other calls outside a scan only count
+        // in the statistics.
+        let elapsed = self.started.elapsed();
+        let node = crate::parallel::current_node() as usize;
+        SCANS.with(|scans| {
+            if let Some(totals) = scans.borrow_mut().get_mut(&node) {
+                totals.calls += 1;
+                totals.rows_received += self.rows_received.get();
+                totals.bytes_received += self.bytes_received.get();
+                totals.bytes_sent += self.bytes_sent;
+                totals.time += elapsed;
+            }
+        });
+
+        update_stats(|stats| {
+            let stats = match stats.entry(key) {
+                Some(stats) => stats,
+                None => return,
+            };
+            stats.calls += 1;
+            stats.rows_sent += self.rows_sent;
+            stats.bytes_sent += self.bytes_sent;
+            stats.rows_received += self.rows_received.get();
+            stats.bytes_received += self.bytes_received.get();
+            if let Some(connect_time) = connect_time {
+                stats.connects += 1;
+                stats.connect_time += connect_time;
+            }
+            if let Some(first_row) = self.first_row.get() {
+                stats.first_rows += 1;
+                stats.first_row_time += first_row - self.started;
+            }
+            stats.latency.record(elapsed);
+        })
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+fn mean_millis(total: Duration, count: u64) -> Option<f64> {
+    match count {
+        0 => None,
+        n => Some(millis(total) / n as f64),
+    }
+}
+
+/// Call statistics gathered by every backend, or by this one unless loaded
+/// through `shared_preload_libraries`, one row per server, table, RPC and
+/// status. Every attempt of a retried call counts.
+#[pg_extern]
+fn grpc_fdw_stats() -> impl std::iter::Iterator<
+    Item = (
+        name!(server_name, String),
+        name!(table_name, String),
+        name!(rpc, String),
+        name!(status, String),
+        name!(calls, i64),
+        name!(rows_sent, i64),
+        name!(bytes_sent, i64),
+        name!(rows_received, i64),
+        name!(bytes_received, i64),
+        name!(connects, i64),
+        name!(mean_connect_ms, Option<f64>),
+        name!(mean_first_row_ms, Option<f64>),
+        name!(mean_ms, f64),
+        name!(p50_ms, f64),
+        name!(p95_ms, f64),
+        name!(p99_ms, f64),
+        name!(max_ms, f64),
+    ),
+> {
+    let mut entries: Vec<(StatsKey, Stats)> = read_stats(|stats| stats.iter().copied().collect());
+    entries.sort_by_key(|(key, _)| *key);
+
+    entries.into_iter().map(|(key, stats)| {
+        (
+            key.server.as_str().to_string(),
+            key.table.as_str().to_string(),
+            key.rpc.as_str().to_string(),
+            retry::code_name(Code::from_i32(key.code)).to_string(),
+            stats.calls as i64,
+            stats.rows_sent as i64,
+            stats.bytes_sent as i64,
+            stats.rows_received as i64,
+            stats.bytes_received as i64,
+            stats.connects as i64,
+            mean_millis(stats.connect_time, stats.connects),
+            mean_millis(stats.first_row_time, stats.first_rows),
+            millis(stats.latency.mean()),
+            millis(stats.latency.quantile(0.5)),
+            millis(stats.latency.quantile(0.95)),
+            millis(stats.latency.quantile(0.99)),
+            millis(stats.latency.max),
+        )
+    })
+}
+
+/// Clears the statistics of `server_name`, or all of them, returning whether
+/// there were any.
+#[pg_extern]
+fn grpc_fdw_stats_reset(server_name: default!(Option<&str>, NULL)) -> bool {
+    update_stats(|stats| {
+        let before = stats.len;
+        stats.retain(|key| server_name.map_or(false, |server| key.server.as_str() != server));
+
+        stats.len != before
+    })
+}
@@ -0,0 +1,137 @@
+/// Days from 1970-01-01 to 2000-01-01, from which Postgres counts the days
+/// and microseconds of its dates and times. They are written here in ISO
+/// 8601 form, whatever the session's `DateStyle`.
+const POSTGRES_EPOCH_DAYS: i64 = 10_957;
+
+const USECS_PER_SEC: i64 = 1_000_000;
+const USECS_PER_MINUTE: i64 = 60 * USECS_PER_SEC;
+const USECS_PER_HOUR: i64 = 60 * USECS_PER_MINUTE;
+const USECS_PER_DAY: i64 = 24 * USECS_PER_HOUR;
+
+/// `2021-03-04`, or `infinity`/`-infinity`.
+pub fn date(days: i32) -> String {
+    match days {
+        i32::MIN => String::from("-infinity"),
+        i32::MAX => String::from("infinity"),
+        days => civil(days as i64),
+    }
+}
+
+/// `05:06:07.5`, with as many fractional digits as needed.
+pub fn time(usecs: i64) -> String {
+    let mut s = format!(
+        "{:02}:{:02}:{:02}",
+        usecs / USECS_PER_HOUR,
+        usecs % USECS_PER_HOUR / USECS_PER_MINUTE,
+        usecs % USECS_PER_MINUTE / USECS_PER_SEC
+    );
+    fraction(&mut s, usecs % USECS_PER_SEC);
+
+    s
+}
+
+/// `05:06:07+02:00`, from the zone in seconds west of UTC as Postgres keeps
+/// it.
+pub fn timetz(usecs: i64, zone: i32) -> String {
+    let east = -zone;
+    let sign = if east < 0 { '-' } else { '+' };
+    let east = east.abs();
+    let mut s = format!(
+        "{}{}{:02}:{:02}",
+        time(usecs),
+        sign,
+        east / 3600,
+        east % 3600 / 60
+    );
+    if east % 60 != 0 {
+        s.push_str(&format!(":{:02}", east % 60));
+    }
+
+    s
+}
+
+/// `2021-03-04T05:06:07`, or `infinity`/`-infinity`.
+pub fn timestamp(usecs: i64) -> String {
+    match usecs {
+        i64::MIN => String::from("-infinity"),
+        i64::MAX => String::from("infinity"),
+        usecs => format!(
+            "{}T{}",
+            civil(usecs.div_euclid(USECS_PER_DAY)),
+            time(usecs.rem_euclid(USECS_PER_DAY))
+        ),
+    }
+}
+
+/// `2021-03-04T05:06:07Z`, Postgres keeps `timestamptz` in UTC.
+pub fn timestamptz(usecs: i64) -> String {
+    match usecs {
+        i64::MIN | i64::MAX => timestamp(usecs),
+        usecs => format!("{}Z", timestamp(usecs)),
+    }
+}
+
+/// An ISO 8601 duration such as `P1Y2M3DT4H5M6.5S`, each part signed on its
+/// own as with `IntervalStyle` `iso_8601`.
+pub fn interval(months: i32, days: i32, usecs: i64) -> String {
+    if months == 0 && days == 0 && usecs == 0 {
+        return String::from("PT0S");
+    }
+
+    let mut s = String::from("P");
+    for (value, unit) in [(months / 12, 'Y'), (months % 12, 'M'), (days, 'D')] {
+        if value != 0 {
+            s.push_str(&format!("{}{}", value, unit));
+        }
+    }
+
+    let hours = usecs / USECS_PER_HOUR;
+    let minutes = usecs % USECS_PER_HOUR / USECS_PER_MINUTE;
+    let seconds = usecs % USECS_PER_MINUTE;
+    if usecs != 0 {
+        s.push('T');
+        if hours != 0 {
+            s.push_str(&format!("{}H", hours));
+        }
+        if minutes != 0 {
+            s.push_str(&format!("{}M", minutes));
+        }
+        if seconds != 0 {
+            if seconds < 0 {
+                s.push('-');
+            }
+            s.push_str(&(seconds.abs() / USECS_PER_SEC).to_string());
+            fraction(&mut s, seconds.abs() % USECS_PER_SEC);
+            s.push('S');
+        }
+    }
+
+    s
+}
+
+fn fraction(s: &mut String, usecs: i64) {
+    if usecs != 0 {
+        s.push_str(format!(".{:06}", usecs).trim_end_matches('0'));
+    }
+}
+
+/// The date `days` after 2000-01-01, years before 1 AD counted
+/// astronomically as ISO 8601 does.
+fn civil(days: i64) -> String {
+    // Howard Hinnant's days_from_civil, inverted.
+    let z = days + POSTGRES_EPOCH_DAYS + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    if year < 0 {
+        format!("-{:04}-{:02}-{:02}", -year, month, day)
+    } else {
+        format!("{:04}-{:02}-{:02}", year, month, day)
+    }
+}
@@ -0,0 +1,241 @@
+use http::Uri;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_rustls::{rustls, TlsConnector};
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Verify {
+    /// Check the certificate chain and that it was issued for the server name.
+    Full,
+    /// Accept any certificate. Traffic is encrypted but not authenticated.
+    None,
+}
+
+pub const VERIFY_MODES: &[&str] = &["full", "none"];
+
+#[derive(Debug, Clone)]
+pub struct TlsOptions {
+    pub ca_file: Option<String>,
+    pub cert_file: Option<String>,
+    pub key_file: Option<String>,
+    pub domain: Option<String>,
+    pub verify: Verify,
+}
+
+impl TlsOptions {
+    /// TLS settings from the server options, or `None` for plaintext.
+    pub fn from_options(uri: &str, server: &HashMap<String, String>) -> Option<Self> {
+        let has_tls_options = server.keys().any(|name| name.starts_with("tls_"));
+        if !uri.starts_with("https://") && !has_tls_options {
+            return None;
+        }
+
+        Some(Self {
+            ca_file: server.get("tls_ca_file").cloned(),
+            cert_file: server.get("tls_cert_file").cloned(),
+            key_file: server.get("tls_key_file").cloned(),
+            domain: server.get("tls_domain").cloned(),
+            verify: match server.get("tls_verify").map(String::as_str) {
+                Some("none") => Verify::None,
+                _ => Verify::Full,
+            },
+        })
+    }
+}
+
+/// Verification is turned off with a connector doing its own handshake,
+/// since tonic only exposes verified TLS.
+#[derive(Clone)]
+pub struct InsecureTls {
+    config: Arc<rustls::ClientConfig>,
+    server_name: ServerName<'static>,
+}
+
+impl std::fmt::Debug for InsecureTls {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("InsecureTls")
+            .field("server_name", &self.server_name)
+            .finish()
+    }
+}
+
+/// Applies the TLS settings to an `https://` endpoint. With verification
+/// off, the endpoint is returned as plain `http://` along with the TLS
+/// settings to pass to [`connect_insecure`], so other settings have to be
+/// applied to the returned endpoint.
+pub fn configure(
+    endpoint: Endpoint,
+    tls: &TlsOptions,
+) -> Result<(Endpoint, Option<InsecureTls>), String> {
+    let uri = endpoint.uri().clone();
+    if uri.scheme_str() != Some("https") {
+        return Err(String::from("TLS options require an https:// server_uri"));
+    }
+    let host = uri.host().unwrap_or_default().to_string();
+
+    match tls.verify {
+        Verify::Full => {
+            let mut config = ClientTlsConfig::new();
+            if let Some(domain) = &tls.domain {
+                config = config.domain_name(domain.clone());
+            }
+            if let Some(ca_file) = &tls.ca_file {
+                config =
+                    config.ca_certificate(Certificate::from_pem(read("tls_ca_file", ca_file)?));
+            }
+            if let Some(client) = identity(tls)? {
+                config = config.identity(Identity::from_pem(client.cert, client.key));
+            }
+
+            let endpoint = endpoint.tls_config(config).map_err(|e| e.to_string())?;
+            Ok((endpoint, None))
+        }
+        Verify::None => {
+            let domain = tls.domain.clone().unwrap_or(host);
+            let server_name = ServerName::try_from(domain.clone())
+                .map_err(|_| format!("\"{}\" is not a valid TLS server name", domain))?;
+            let insecure = InsecureTls {
+                config: Arc::new(insecure_config(tls)?),
+                server_name,
+            };
+
+            // tonic adds its own verified TLS to any https:// endpoint.
+            let port = uri.port_u16().unwrap_or(443);
+            let authority = match uri.host() {
+                Some(host) if host.contains(':') && !host.starts_with('[') => {
+                    format!("[{}]:{}", host, port)
+                }
+                Some(host) => format!("{}:{}", host, port),
+                None => return Err(String::from("server URI has no host")),
+            };
+            let path = uri.path_and_query().map_or("", |p| p.as_str());
+            let plain = Endpoint::from_shared(format!("http://{}{}", authority, path))
+                .map_err(|e| e.to_string())?;
+
+            Ok((plain, Some(insecure)))
+        }
+    }
+}
+
+/// Connects over TLS without verifying the server certificate.
+pub async fn connect_insecure(
+    endpoint: &Endpoint,
+    tls: &InsecureTls,
+    nodelay: bool,
+) -> Result<Channel, tonic::transport::Error> {
+    let tls = tls.clone();
+    endpoint
+        .connect_with_connector(tower::service_fn(move |uri: Uri| {
+            let tls = tls.clone();
+            async move {
+                let host = uri
+                    .host()
+                    .unwrap_or_default()
+                    .trim_matches(|c| c == '[' || c == ']');
+                let port = uri.port_u16().unwrap_or(443);
+                let tcp = TcpStream::connect((host, port)).await?;
+                tcp.set_nodelay(nodelay)?;
+
+                TlsConnector::from(tls.config)
+                    .connect(tls.server_name, tcp)
+                    .await
+            }
+        }))
+        .await
+}
+
+fn read(option: &str, path: &str) -> Result<Vec<u8>, String> {
+    std::fs::read(path).map_err(|e| format!("could not read {} \"{}\": {}", option, path, e))
+}
+
+/// PEM encoded client certificate and private key.
+struct ClientIdentity {
+    cert: Vec<u8>,
+    key: Vec<u8>,
+}
+
+fn identity(tls: &TlsOptions) -> Result<Option<ClientIdentity>, String> {
+    match (&tls.cert_file, &tls.key_file) {
+        (Some(cert), Some(key)) => Ok(Some(ClientIdentity {
+            cert: read("tls_cert_file", cert)?,
+            key: read("tls_key_file", key)?,
+        })),
+        (None, None) => Ok(None),
+        _ => Err(String::from(
+            "tls_cert_file and tls_key_file must be set together",
+        )),
+    }
+}
+
+fn insecure_config(tls: &TlsOptions) -> Result<rustls::ClientConfig, String> {
+    let builder = rustls::ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AcceptAnyCertificate));
+
+    let mut config = match identity(tls)? {
+        Some(client) => {
+            let certs = rustls_pemfile::certs(&mut &client.cert[..])
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|_| String::from("could not parse tls_cert_file"))?;
+            let key = rustls_pemfile::private_key(&mut &client.key[..])
+                .map_err(|_| String::from("could not parse tls_key_file"))?
+                .ok_or_else(|| String::from("tls_key_file contains no private key"))?;
+
+            builder
+                .with_client_auth_cert(certs, key)
+                .map_err(|e| e.to_string())?
+        }
+        None => builder.with_no_client_auth(),
+    };
+    config.alpn_protocols = vec![b"h2".to_vec()];
+
+    Ok(config)
+}
+
+#[derive(Debug)]
+struct AcceptAnyCertificate;
+
+impl ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &signature_algorithms())
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &signature_algorithms())
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        signature_algorithms().supported_schemes()
+    }
+}
+
+fn signature_algorithms() -> WebPkiSupportedAlgorithms {
+    rustls::crypto::ring::default_provider().signature_verification_algorithms
+}
@@ -0,0 +1,100 @@
+use std::fmt::Write;
+
+/// W3C trace context of a span, sent as `traceparent` and `tracestate`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceContext {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub flags: u8,
+    pub state: Option<String>,
+}
+
+/// Set on traces started by grpc_fdw itself.
+const SAMPLED: u8 = 0x01;
+
+impl TraceContext {
+    /// A new span, child of `parent` if there is one and the root of a new
+    /// sampled trace otherwise. `random` supplies the new ids.
+    pub fn start(parent: Option<&TraceContext>, random: [u8; 24]) -> Self {
+        let mut span_id = [0; 8];
+        span_id.copy_from_slice(&random[16..]);
+
+        match parent {
+            Some(parent) => Self {
+                span_id,
+                ..parent.clone()
+            },
+            None => {
+                let mut trace_id = [0; 16];
+                trace_id.copy_from_slice(&random[..16]);
+
+                Self {
+                    trace_id,
+                    span_id,
+                    flags: SAMPLED,
+                    state: None,
+                }
+            }
+        }
+    }
+
+    /// Parses a `traceparent` header, along with its `tracestate`.
+    pub fn parse(traceparent: &str, state: Option<&str>) -> Result<Self, String> {
+        let invalid = || format!("\"{}\" is not a valid traceparent", traceparent);
+        let fields: Vec<&str> = traceparent.trim().split('-').collect();
+        if fields.len() < 4 {
+            return Err(invalid());
+        }
+
+        // Later versions may append fields, version 00 has exactly four.
+        let version = hex::<1>(fields[0]).ok_or_else(invalid)?;
+        if version == [0xff] || (version == [0x00] && fields.len() != 4) {
+            return Err(invalid());
+        }
+
+        let trace_id = hex::<16>(fields[1]).ok_or_else(invalid)?;
+        let span_id = hex::<8>(fields[2]).ok_or_else(invalid)?;
+        let flags = hex::<1>(fields[3]).ok_or_else(invalid)?;
+        if trace_id == [0; 16] || span_id == [0; 8] {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            trace_id,
+            span_id,
+            flags: flags[0],
+            state: state
+                .map(str::trim)
+                .filter(|state| !state.is_empty())
+                .map(String::from),
+        })
+    }
+
+    pub fn traceparent(&self) -> String {
+        let mut s = String::from("00-");
+        for byte in self.trace_id.iter() {
+            write!(s, "{:02x}", byte).unwrap();
+        }
+        s.push('-');
+        for byte in self.span_id.iter() {
+            write!(s, "{:02x}", byte).unwrap();
+        }
+        write!(s, "-{:02x}", self.flags).unwrap();
+
+        s
+    }
+}
+
+/// Decodes exactly `N` bytes of lowercase hex.
+fn hex<const N: usize>(s: &str) -> Option<[u8; N]> {
+    if s.len() != N * 2 || !s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+        return None;
+    }
+
+    let mut bytes = [0; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).ok()?;
+    }
+
+    Some(bytes)
+}
@@ -0,0 +1,25 @@
+use tokio::net::UnixStream;
+use tonic::transport::{Channel, Endpoint, Uri};
+
+const SCHEME: &str = "unix://";
+
+/// The socket path of a `unix:///path/to.sock` server URI.
+pub fn socket_path(uri: &str) -> Option<&str> {
+    uri.strip_prefix(SCHEME)
+}
+
+/// HTTP/2 still needs an authority, so unix sockets are dialled through a
+/// placeholder endpoint that carries the other settings.
+pub fn endpoint() -> Endpoint {
+    Endpoint::from_static("http://localhost")
+}
+
+pub async fn connect(endpoint: &Endpoint, path: &str) -> Result<Channel, tonic::transport::Error> {
+    let path = path.to_string();
+
+    endpoint
+        .connect_with_connector(tower::service_fn(move |_: Uri| {
+            UnixStream::connect(path.clone())
+        }))
+        .await
+}